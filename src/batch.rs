@@ -0,0 +1,317 @@
+//! Matches the documents of a directory to the entries of a bibliography in order to import
+//! them all at once.
+
+use import::{file_field_paths, BibliographyFormat};
+use model::LibraryEntryMeta;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The minimal similarity between a file name and a title to be considered a match.
+const TITLE_SIMILARITY_THRESHOLD: f64 = 0.6;
+
+/// Describes how a document was matched to a bibliography entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMethod {
+    /// The entry references the document in its `file` tag
+    FileTag,
+    /// The file name contains the citation key
+    CitationKey,
+    /// The file name resembles the title
+    Title,
+}
+
+/// A document proposed to be imported using a bibliography entry.
+#[derive(Debug, Clone)]
+pub struct FileMatch {
+    pub path: PathBuf,
+    pub entry: usize,
+    pub method: MatchMethod,
+}
+
+impl fmt::Display for MatchMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MatchMethod::FileTag => "file tag",
+            MatchMethod::CitationKey => "citation key",
+            MatchMethod::Title => "title",
+        })
+    }
+}
+
+/// Lists all documents in a directory and its subdirectories, skipping hidden files and
+/// bibliographies.
+pub fn list_documents(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut documents = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+            if hidden {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if path
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(BibliographyFormat::from_extension)
+                .is_none()
+            {
+                documents.push(path);
+            }
+        }
+    }
+    documents.sort();
+
+    Ok(documents)
+}
+
+/// Splits a text into its lower case words for fuzzy comparisons.
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 2)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Calculates the Dice coefficient of the words of a file name and a title.
+fn title_similarity(file_stem: &str, title: &str) -> f64 {
+    let file_words = words(file_stem);
+    let title_words = words(title);
+    if file_words.is_empty() || title_words.is_empty() {
+        return 0.0;
+    }
+    let common = file_words.intersection(&title_words).count();
+
+    2.0 * common as f64 / (file_words.len() + title_words.len()) as f64
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Keeps track of the proposed matches, making sure every document and every entry is matched
+/// at most once.
+#[derive(Default)]
+struct Matching {
+    matches: Vec<(usize, usize, MatchMethod)>,
+    documents: HashSet<usize>,
+    entries: HashSet<usize>,
+}
+
+impl Matching {
+    fn add(&mut self, document: usize, entry: usize, method: MatchMethod) {
+        if !self.documents.contains(&document) && !self.entries.contains(&entry) {
+            self.documents.insert(document);
+            self.entries.insert(entry);
+            self.matches.push((document, entry, method));
+        }
+    }
+}
+
+/// Proposes which document should be imported using which bibliography entry. Documents are
+/// first matched using the `file` tags of the entries (resolved relative to `bib_dir`), then by
+/// citation keys contained in the file names and finally by file names resembling the titles.
+pub fn match_documents(
+    documents: &[PathBuf],
+    entries: &[LibraryEntryMeta],
+    bib_dir: &Path,
+) -> Vec<FileMatch> {
+    let mut matching = Matching::default();
+    let canonical: Vec<Option<PathBuf>> = documents.iter().map(|d| d.canonicalize().ok()).collect();
+    let stems: Vec<String> = documents.iter().map(|d| file_stem(d)).collect();
+
+    for (e, entry) in entries.iter().enumerate() {
+        for path in file_field_paths(entry, bib_dir) {
+            let path = path.canonicalize().ok();
+            if let Some(d) = canonical.iter().position(|c| c.is_some() && *c == path) {
+                matching.add(d, e, MatchMethod::FileTag);
+            }
+        }
+    }
+
+    // Prefer file names equal to the key over file names only containing it
+    for &exact in &[true, false] {
+        for (e, entry) in entries.iter().enumerate() {
+            let key = entry.key().to_lowercase();
+            if key.chars().count() < 3 || matching.entries.contains(&e) {
+                continue;
+            }
+            let found = stems.iter().enumerate().position(|(d, stem)| {
+                !matching.documents.contains(&d)
+                    && if exact {
+                        *stem == key
+                    } else {
+                        stem.contains(&key)
+                    }
+            });
+            if let Some(d) = found {
+                matching.add(d, e, MatchMethod::CitationKey);
+            }
+        }
+    }
+
+    let mut candidates: Vec<(f64, usize, usize)> = Vec::new();
+    for (d, stem) in stems.iter().enumerate() {
+        for (e, entry) in entries.iter().enumerate() {
+            let similarity = title_similarity(stem, entry.title());
+            if similarity >= TITLE_SIMILARITY_THRESHOLD {
+                candidates.push((similarity, d, e));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    for (_, d, e) in candidates {
+        matching.add(d, e, MatchMethod::Title);
+    }
+
+    let mut matches: Vec<FileMatch> = matching
+        .matches
+        .into_iter()
+        .map(|(d, entry, method)| FileMatch {
+            path: documents[d].clone(),
+            entry,
+            method,
+        })
+        .collect();
+    matches.sort_by(|a, b| a.path.cmp(&b.path));
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::{LibraryEntryType, TagMap};
+    use std::fs::File;
+
+    fn entry(key: &str, title: &str, file: Option<&str>) -> LibraryEntryMeta {
+        let mut tags = TagMap::new();
+        if let Some(file) = file {
+            tags.insert(String::from("file"), String::from(file));
+        }
+        LibraryEntryMeta::new(
+            String::from(key),
+            LibraryEntryType::Article,
+            String::from(title),
+            vec![String::from("Doe, John")],
+            2020,
+            None,
+            Some(tags),
+        )
+    }
+
+    /// Matches the documents and describes the matches like `paper.pdf -> 0 by title`.
+    fn matches(documents: &[&str], entries: &[LibraryEntryMeta], dir: &Path) -> Vec<String> {
+        let documents: Vec<PathBuf> = documents.iter().map(|d| dir.join(d)).collect();
+        match_documents(&documents, entries, dir)
+            .into_iter()
+            .map(|m| {
+                let name = m.path.file_name().unwrap().to_string_lossy();
+                format!("{} -> {} by {}", name, m.entry, m.method)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn title_similarity_compares_words() {
+        assert_eq!(
+            title_similarity("graph-neural_networks", "Graph Neural Networks"),
+            1.0
+        );
+        assert_eq!(
+            title_similarity("graph-networks", "Graph Neural Networks"),
+            0.8
+        );
+        assert_eq!(title_similarity("scan0001", "Graph Neural Networks"), 0.0);
+        assert_eq!(title_similarity("a-b", "A B"), 0.0);
+    }
+
+    #[test]
+    fn match_by_file_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        File::create(dir.path().join("download.pdf")).unwrap();
+        let entries = [
+            entry("doe2020", "Unrelated", Some(":download.pdf:PDF")),
+            entry("download", "Download", None),
+        ];
+        assert_eq!(
+            matches(&["download.pdf"], &entries, dir.path()),
+            ["download.pdf -> 0 by file tag"]
+        );
+    }
+
+    #[test]
+    fn match_by_citation_key() {
+        let dir = Path::new("/nonexistent");
+        let entries = [
+            entry("doe2020", "Unrelated", None),
+            entry("roe2019", "Unrelated", None),
+            entry("ab", "Unrelated", None),
+        ];
+        assert_eq!(
+            matches(
+                &[
+                    "doe2020-notes.pdf",
+                    "Doe2020.pdf",
+                    "roe2019_final.pdf",
+                    "ab.pdf"
+                ],
+                &entries,
+                dir
+            ),
+            [
+                "Doe2020.pdf -> 0 by citation key",
+                "roe2019_final.pdf -> 1 by citation key",
+            ]
+        );
+    }
+
+    #[test]
+    fn match_by_title() {
+        let dir = Path::new("/nonexistent");
+        let entries = [
+            entry("doe2020", "Graph Neural Networks", None),
+            entry("doe2020a", "Neural Networks for Graphs", None),
+            entry("roe2019", "Something Else Entirely", None),
+        ];
+        assert_eq!(
+            matches(
+                &[
+                    "graph_neural_networks.pdf",
+                    "neural-networks-graphs.pdf",
+                    "else.pdf"
+                ],
+                &entries,
+                dir
+            ),
+            [
+                "graph_neural_networks.pdf -> 0 by title",
+                "neural-networks-graphs.pdf -> 1 by title",
+            ]
+        );
+    }
+
+    #[test]
+    fn documents_and_entries_are_matched_once() {
+        let dir = tempfile::tempdir().unwrap();
+        File::create(dir.path().join("doe2020.pdf")).unwrap();
+        let entries = [
+            entry("roe2019", "Other", Some("doe2020.pdf")),
+            entry("doe2020", "Other", None),
+        ];
+        assert_eq!(
+            matches(&["doe2020.pdf", "roe2019-copy.pdf"], &entries, dir.path()),
+            ["doe2020.pdf -> 0 by file tag"]
+        );
+    }
+}
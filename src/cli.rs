@@ -1,31 +1,131 @@
+use batch;
 use clap::{App, ArgMatches};
 use configuration::Configuration;
-use import::import;
-use library::Library;
+use digest;
+use fsck;
+use import::{
+    file_field_paths, import, import_digested, load_bibliography, BibliographyFormat,
+    BibliographySource, ImportError, ImportOptions, ImportResultSet,
+};
+use library::{Library, QueryError, QueryParams};
+use model::{Identifier, LibraryEntry, LibraryEntryMeta};
+use rename;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tags;
+use views;
 
-pub fn process_args(conf: &Configuration, lib: &mut Library) {
+/// Parses the command line arguments, executes the given subcommand and returns the exit code
+/// the process should terminate with.
+pub fn process_args(conf: &Configuration, lib: &mut Library) -> i32 {
     let app_yaml = load_yaml!("cli_en.yml");
     let matches = App::from_yaml(app_yaml).get_matches();
 
-    match matches.subcommand() {
+    let success = match matches.subcommand() {
         ("import", Some(sub)) => sub_import(sub, lib, conf),
-        _ => (),
+        ("query", Some(sub)) => sub_query(sub, lib),
+        ("remove", Some(sub)) => sub_remove(sub, lib),
+        ("open", Some(sub)) => sub_open(sub, lib, conf),
+        ("fsck", Some(sub)) => sub_fsck(sub, lib, conf),
+        ("relink", Some(sub)) => sub_relink(sub, lib, conf),
+        ("rename", Some(sub)) => sub_rename(sub, lib, conf),
+        ("tag", Some(sub)) => sub_tag(sub, lib, conf),
+        ("views", Some(sub)) => sub_views(sub, lib, conf),
+        _ => true,
+    };
+
+    if success {
+        0
+    } else {
+        1
     }
 }
 
-fn sub_import(sub: &ArgMatches, lib: &mut Library, conf: &Configuration) {
+fn sub_import(sub: &ArgMatches, lib: &mut Library, conf: &Configuration) -> bool {
     let file = sub.value_of("file").unwrap();
-    let bibliography = sub.value_of("bibliography").unwrap();
+    let identifier = sub.value_of("doi").map(Identifier::doi).or_else(|| {
+        sub.value_of("arxiv")
+            .map(|a| Identifier::Arxiv(String::from(a)))
+    });
+    let source = if let Some(ref id) = identifier {
+        BibliographySource::Identifier(id)
+    } else if sub.is_present("clipboard") {
+        BibliographySource::Clipboard
+    } else {
+        match sub.value_of("bibliography") {
+            Some("-") => BibliographySource::Stdin,
+            Some(p) => BibliographySource::File(Path::new(p)),
+            None => BibliographySource::Document(Path::new(file)),
+        }
+    };
+    // A bibliography given without a document refers to the documents in its `file` tags
+    let attachments = match source {
+        BibliographySource::Document(p) if !sub.is_present("batch") => p
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(BibliographyFormat::from_extension)
+            .is_some(),
+        _ => false,
+    };
+    let source = if attachments {
+        BibliographySource::File(Path::new(file))
+    } else {
+        source
+    };
+    let format = match sub.value_of("format").map(str::parse::<BibliographyFormat>) {
+        Some(Ok(f)) => Some(f),
+        Some(Err(err)) => {
+            eprintln!("Failed to import file: {}.", err);
+            return false;
+        }
+        None => None,
+    };
     let id = sub.value_of("entry");
-    let force_move = sub.is_present("move");
-    let force_copy = sub.is_present("copy");
-    let tags: Vec<String> = sub
-        .values_of("tag")
-        .map_or_else(|| vec![], |t| t.map(String::from).collect());
+    let options = ImportOptions {
+        force_move: sub.is_present("move"),
+        force_copy: sub.is_present("copy"),
+        allow_duplicate: sub.is_present("allow_duplicate"),
+        tags: sub
+            .values_of("tag")
+            .map_or_else(Vec::new, |t| t.map(String::from).collect()),
+    };
+
+    if sub.is_present("batch") {
+        if let BibliographySource::Document(_) = source {
+            eprintln!("Batch imports require a bibliography.");
+            return false;
+        }
+    }
+
+    let bibliographies = match load_bibliography(source, format, conf) {
+        Ok(b) => b,
+        Err(err) => {
+            eprintln!("Failed to load bibliography: {}.", err);
+            return false;
+        }
+    };
 
-    match import(file, bibliography, id, force_move, force_copy, tags, conf) {
+    if sub.is_present("batch") || attachments {
+        let bib_dir = match source {
+            BibliographySource::File(p) => p.parent().unwrap_or_else(|| Path::new(".")),
+            _ => Path::new("."),
+        };
+        let options = BatchOptions {
+            import: options,
+            skip_confirmation: sub.is_present("yes"),
+        };
+        return if attachments {
+            import_attachments(bibliographies, id, bib_dir, options, lib, conf)
+        } else {
+            import_batch(Path::new(file), bibliographies, bib_dir, options, lib, conf)
+        };
+    }
+
+    match import(file, bibliographies, id, &options, lib, conf) {
         Ok(entry) => {
-            let paths: String = (&entry)
+            let paths: String = entry
                 .file_paths()
                 .iter()
                 .enumerate()
@@ -38,10 +138,615 @@ fn sub_import(sub: &ArgMatches, lib: &mut Library, conf: &Configuration) {
                 })
                 .collect();
             println!("Successfully imported file to {}.", &paths);
-            lib.add_entry(entry);
+            true
         }
         Err(err) => {
             eprintln!("Failed to import file: {}.", err);
+            print_duplicate(&err);
+            false
+        }
+    }
+}
+
+/// Prints the existing entry if the import failed because of a duplicate.
+fn print_duplicate(err: &ImportError) {
+    if let ImportError::Duplicate(existing, _) = err {
+        print_entry_table(&[existing]);
+        eprintln!("Use --allow-duplicate to import it anyway.");
+    }
+}
+
+/// Options applied to every document of a batch import or an import of attachments.
+struct BatchOptions {
+    import: ImportOptions,
+    skip_confirmation: bool,
+}
+
+/// Imports all documents of a directory which can be matched to an entry of the bibliography.
+fn import_batch(
+    dir: &Path,
+    bibliographies: ImportResultSet,
+    bib_dir: &Path,
+    options: BatchOptions,
+    lib: &mut Library,
+    conf: &Configuration,
+) -> bool {
+    let documents = match batch::list_documents(dir) {
+        Ok(d) => d,
+        Err(err) => {
+            eprintln!("Failed to list documents in {}: {}.", dir.display(), err);
+            return false;
+        }
+    };
+    let matches = batch::match_documents(&documents, &bibliographies, bib_dir);
+
+    let unmatched: Vec<&PathBuf> = documents
+        .iter()
+        .filter(|d| !matches.iter().any(|m| m.path == **d))
+        .collect();
+    if !unmatched.is_empty() {
+        println!("Documents without a matching entry:");
+        for d in unmatched {
+            println!("  {}", d.display());
+        }
+    }
+    if matches.is_empty() {
+        eprintln!("No documents could be matched to the bibliography.");
+        return false;
+    }
+
+    import_matches(&matches, &bibliographies, options, lib, conf)
+}
+
+/// Imports the documents referenced in the `file` tags of all entries of the bibliography, or
+/// only of the entry with the given key.
+fn import_attachments(
+    bibliographies: ImportResultSet,
+    key: Option<&str>,
+    bib_dir: &Path,
+    options: BatchOptions,
+    lib: &mut Library,
+    conf: &Configuration,
+) -> bool {
+    let mut matches: Vec<batch::FileMatch> = Vec::new();
+    for (i, meta) in bibliographies.iter().enumerate() {
+        if key.is_some_and(|k| k != meta.key()) {
+            continue;
+        }
+        let paths = file_field_paths(meta, bib_dir);
+        if paths.is_empty() {
+            println!("Entry {} references no document.", meta.key());
+        }
+        for path in paths {
+            if path.is_file() {
+                matches.push(batch::FileMatch {
+                    path,
+                    entry: i,
+                    method: batch::MatchMethod::FileTag,
+                });
+            } else {
+                eprintln!(
+                    "Document {} of entry {} not found.",
+                    path.display(),
+                    meta.key()
+                );
+            }
+        }
+    }
+    if matches.is_empty() {
+        eprintln!("The bibliography references no existing documents.");
+        return false;
+    }
+
+    import_matches(&matches, &bibliographies, options, lib, conf)
+}
+
+/// Imports the documents proposed by `matches` after letting the user confirm them. Failing
+/// imports are reported without aborting the remaining imports.
+fn import_matches(
+    matches: &[batch::FileMatch],
+    bibliographies: &[LibraryEntryMeta],
+    options: BatchOptions,
+    lib: &mut Library,
+    conf: &Configuration,
+) -> bool {
+    println!("Proposed imports:");
+    for m in matches {
+        println!(
+            "  {} -> {} (by {})",
+            m.path.display(),
+            bibliographies[m.entry].key(),
+            m.method
+        );
+    }
+    if !options.skip_confirmation && !confirm(&format!("Import {} document(s)?", matches.len())) {
+        println!("Nothing imported.");
+        return true;
+    }
+
+    let paths: Vec<&Path> = matches.iter().map(|m| m.path.as_path()).collect();
+    let digests = digest::calculate_all(&paths, conf.variables().digest_threads(), Some("Hashing"));
+
+    let mut failed = 0;
+    for (m, digest) in matches.iter().zip(digests) {
+        let meta = bibliographies[m.entry].clone();
+        let result = digest.map_err(ImportError::from).and_then(|d| {
+            import_digested(&m.path, d, vec![meta], None, &options.import, lib, conf)
+        });
+        match result {
+            Ok(entry) => {
+                println!(
+                    "Imported {} to {}.",
+                    m.path.display(),
+                    entry.file_paths().join("; ")
+                );
+            }
+            Err(err) => {
+                eprintln!("Failed to import {}: {}.", m.path.display(), err);
+                print_duplicate(&err);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "Successfully imported {} of {} document(s).",
+        matches.len() - failed,
+        matches.len()
+    );
+
+    failed == 0
+}
+
+/// Assembles the query parameters from the arguments shared by all querying subcommands.
+fn query_params<'a>(sub: &'a ArgMatches) -> QueryParams<'a> {
+    QueryParams::new(
+        sub.value_of("author"),
+        sub.value_of("year"),
+        sub.value_of("title"),
+        sub.value_of("type"),
+        sub.value_of("tag"),
+        sub.value_of("text"),
+        sub.value_of("query"),
+    )
+}
+
+/// Asks the user a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    if io::stdout().flush().is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => {
+            let answer = answer.trim().to_lowercase();
+            answer == "y" || answer == "yes"
+        }
+        Err(_) => false,
+    }
+}
+
+/// Lets the user pick one of the given entries on the terminal. Returns `None` if the
+/// selection was aborted.
+fn choose_entry<'a>(entries: &[&'a LibraryEntry]) -> Option<&'a LibraryEntry> {
+    if entries.len() == 1 {
+        return Some(entries[0]);
+    }
+
+    println!("Multiple documents match the query:");
+    for (i, e) in entries.iter().enumerate() {
+        println!(
+            "[{}] {}: {} ({}, {})",
+            i + 1,
+            e.meta().key(),
+            e.meta().title(),
+            e.meta().authors().join("; "),
+            e.meta().year()
+        );
+    }
+    loop {
+        print!(
+            "Select a document [1-{}] or press enter to abort: ",
+            entries.len()
+        );
+        io::stdout().flush().ok()?;
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return None;
+        }
+        match answer.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= entries.len() => return Some(entries[n - 1]),
+            _ => eprintln!("Invalid selection {}.", answer),
+        }
+    }
+}
+
+fn sub_query(sub: &ArgMatches, lib: &Library) -> bool {
+    match lib.query(&query_params(sub)) {
+        Ok(entries) => {
+            print_entry_table(&entries);
+            true
+        }
+        Err(QueryError::NoMatch) => {
+            eprintln!("No documents matching the query found.");
+            false
+        }
+        Err(err) => {
+            eprintln!("Failed to query library: {}", err);
+            false
+        }
+    }
+}
+
+fn sub_remove(sub: &ArgMatches, lib: &mut Library) -> bool {
+    let delete = sub.is_present("delete");
+    let skip_confirmation = sub.is_present("yes");
+
+    let result = lib.remove_entry(&query_params(sub), delete, |entries| {
+        print_entry_table(&entries);
+        if skip_confirmation {
+            return true;
+        }
+        let question = if delete {
+            format!(
+                "Remove {} document(s) and delete their files?",
+                entries.len()
+            )
+        } else {
+            format!("Remove {} document(s) from the library?", entries.len())
+        };
+        confirm(&question)
+    });
+
+    match result {
+        Ok(0) => {
+            println!("Nothing removed.");
+            true
+        }
+        Ok(n) => {
+            println!("Successfully removed {} document(s).", n);
+            true
         }
+        Err(QueryError::NoMatch) => {
+            eprintln!("No documents matching the query found.");
+            false
+        }
+        Err(err) => {
+            eprintln!("Failed to remove documents: {}", err);
+            false
+        }
+    }
+}
+
+fn sub_open(sub: &ArgMatches, lib: &Library, conf: &Configuration) -> bool {
+    let entries = match lib.query(&query_params(sub)) {
+        Ok(entries) => entries,
+        Err(QueryError::NoMatch) => {
+            eprintln!("No documents matching the query found.");
+            return false;
+        }
+        Err(err) => {
+            eprintln!("Failed to query library: {}", err);
+            return false;
+        }
+    };
+    let entry = match choose_entry(&entries) {
+        Some(e) => e,
+        None => return true,
+    };
+    let path = match entry.file_paths().first() {
+        Some(p) => Path::new(p),
+        None => {
+            eprintln!("Document {} has no file.", entry.meta().key());
+            return false;
+        }
+    };
+
+    let viewer = conf
+        .variables()
+        .viewer_for(path.extension().and_then(|e| e.to_str()));
+    let mut viewer_args = viewer.split_whitespace();
+    let program = match viewer_args.next() {
+        Some(p) => p,
+        None => {
+            eprintln!("No viewer configured.");
+            return false;
+        }
+    };
+
+    match Command::new(program).args(viewer_args).arg(path).spawn() {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("Failed to launch viewer {}: {}", program, err);
+            false
+        }
+    }
+}
+
+fn sub_fsck(sub: &ArgMatches, lib: &Library, conf: &Configuration) -> bool {
+    let issues = match fsck::verify(lib, conf) {
+        Ok(issues) => issues,
+        Err(err) => {
+            eprintln!("Failed to verify library: {}", err);
+            return false;
+        }
+    };
+    if issues.is_empty() {
+        println!("No problems found.");
+        return true;
+    }
+
+    let fix = sub.is_present("fix");
+    let mut remaining = 0;
+    for issue in &issues {
+        if !(fix && issue.fixable()) {
+            println!("{}", issue);
+            remaining += 1;
+            continue;
+        }
+        match issue.fix(conf.variables().tag_link_mode()) {
+            Ok(()) => println!("{} (fixed)", issue),
+            Err(err) => {
+                println!("{} (fixing failed: {})", issue, err);
+                remaining += 1;
+            }
+        }
+    }
+    let fixable = issues.iter().filter(|i| i.fixable()).count();
+    if fix {
+        println!(
+            "Found {} problem(s), {} remaining.",
+            issues.len(),
+            remaining
+        );
+    } else if fixable > 0 {
+        println!(
+            "Found {} problem(s); {} can be fixed using --fix.",
+            issues.len(),
+            fixable
+        );
+    } else {
+        println!("Found {} problem(s).", issues.len());
+    }
+
+    remaining == 0
+}
+
+fn sub_relink(sub: &ArgMatches, lib: &mut Library, conf: &Configuration) -> bool {
+    let dirs: Vec<PathBuf> = match sub.values_of("directory") {
+        Some(d) => d.map(PathBuf::from).collect(),
+        None => vec![conf.variables().document_location().to_path_buf()],
+    };
+
+    match fsck::relink(lib, &dirs, conf) {
+        Ok(ref relinked) if relinked.is_empty() => {
+            println!("No documents relinked.");
+            true
+        }
+        Ok(relinked) => {
+            for r in &relinked {
+                println!("{}: {} -> {}", r.key, r.old, r.new);
+            }
+            println!("Successfully relinked {} file(s).", relinked.len());
+            true
+        }
+        Err(err) => {
+            eprintln!("Failed to relink documents: {}", err);
+            false
+        }
+    }
+}
+
+fn sub_rename(sub: &ArgMatches, lib: &mut Library, conf: &Configuration) -> bool {
+    let entries = match lib.query_indices(&query_params(sub)) {
+        Ok(entries) => entries,
+        Err(QueryError::NoMatch) => {
+            eprintln!("No documents matching the query found.");
+            return false;
+        }
+        Err(err) => {
+            eprintln!("Failed to query library: {}", err);
+            return false;
+        }
+    };
+    let renames = rename::plan(lib, &entries, conf);
+    if renames.is_empty() {
+        println!("All documents are already named according to the pattern.");
+        return true;
+    }
+
+    for r in &renames {
+        for (old, new) in &r.moves {
+            println!("{}\n  -> {}", old, new);
+        }
+    }
+    if sub.is_present("dry_run") {
+        return true;
+    }
+    if !sub.is_present("yes") && !confirm(&format!("Rename {} document(s)?", renames.len())) {
+        println!("Nothing renamed.");
+        return true;
+    }
+
+    let mut failed = 0;
+    for r in &renames {
+        if let Err(err) = rename::apply(lib, r) {
+            eprintln!(
+                "Failed to rename {}: {}.",
+                lib.entries()[r.entry].meta().key(),
+                err
+            );
+            failed += 1;
+        }
+    }
+    println!(
+        "Successfully renamed {} of {} document(s).",
+        renames.len() - failed,
+        renames.len()
+    );
+
+    failed == 0
+}
+
+fn sub_tag(sub: &ArgMatches, lib: &mut Library, conf: &Configuration) -> bool {
+    match sub.subcommand() {
+        ("list", Some(_)) => {
+            let tags = tags::list(lib);
+            if tags.is_empty() {
+                println!("No tags in use.");
+            }
+            // Children are listed indented below their parents
+            for (tag, count) in tags {
+                let depth = tag.matches(tags::SEPARATOR).count();
+                let name = tag.rsplit(tags::SEPARATOR).next().unwrap_or(&tag);
+                println!("{}{} ({})", "  ".repeat(depth), name, count);
+            }
+            true
+        }
+        ("rename", Some(sub)) => {
+            let old = sub.value_of("old").unwrap();
+            let new = sub.value_of("new").unwrap();
+            match tags::rename(lib, old, new, conf) {
+                Ok(0) => {
+                    eprintln!("No documents tagged with {} found.", old);
+                    false
+                }
+                Ok(n) => {
+                    println!("Successfully renamed tag {} of {} document(s).", old, n);
+                    true
+                }
+                Err(err) => {
+                    eprintln!("Failed to rename tag {}: {}.", old, err);
+                    false
+                }
+            }
+        }
+        (action, Some(sub)) => {
+            let tag = sub.value_of("name").unwrap();
+            let entries = match lib.query_indices(&query_params(sub)) {
+                Ok(entries) => entries,
+                Err(QueryError::NoMatch) => {
+                    eprintln!("No documents matching the query found.");
+                    return false;
+                }
+                Err(err) => {
+                    eprintln!("Failed to query library: {}", err);
+                    return false;
+                }
+            };
+
+            let mut changed = 0;
+            let mut failed = 0;
+            for i in entries {
+                let result = if action == "add" {
+                    tags::add(lib, i, tag, conf)
+                } else {
+                    tags::remove(lib, i, tag, conf)
+                };
+                match result {
+                    Ok(true) => changed += 1,
+                    Ok(false) => (),
+                    Err(err) => {
+                        eprintln!(
+                            "Failed to update tags of {}: {}.",
+                            lib.entries()[i].meta().key(),
+                            err
+                        );
+                        failed += 1;
+                    }
+                }
+            }
+            println!("Successfully updated {} document(s).", changed);
+
+            failed == 0
+        }
+        _ => true,
+    }
+}
+
+fn sub_views(sub: &ArgMatches, lib: &Library, conf: &Configuration) -> bool {
+    let sub = match sub.subcommand() {
+        ("sync", Some(sub)) => sub,
+        _ => return true,
+    };
+    if conf.variables().views().is_empty() {
+        eprintln!("No views defined in the configuration.");
+        return false;
+    }
+
+    let dry_run = sub.is_present("dry_run");
+    match views::sync(lib, conf, dry_run) {
+        Ok(report) => {
+            for link in &report.removed {
+                println!("- {}", link.display());
+            }
+            for link in &report.created {
+                println!("+ {}", link.display());
+            }
+            println!(
+                "{} {} link(s), removed {} and kept {}.",
+                if dry_run { "Would create" } else { "Created" },
+                report.created.len(),
+                report.removed.len(),
+                report.unchanged
+            );
+            true
+        }
+        Err(err) => {
+            eprintln!("Failed to synchronize views: {}", err);
+            false
+        }
+    }
+}
+
+/// Prints the given entries as a table containing their key, authors, year, title and paths.
+fn print_entry_table(entries: &[&LibraryEntry]) {
+    let header = ["Key", "Authors", "Year", "Title", "Paths"];
+    let rows: Vec<[String; 5]> = entries
+        .iter()
+        .map(|e| {
+            [
+                String::from(e.meta().key()),
+                e.meta().authors().join("; "),
+                e.meta().year().to_string(),
+                String::from(e.meta().title()),
+                e.file_paths().join("; "),
+            ]
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(c, &w)| format!("{:w$}", c, w = w))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!("{}", format_row(header.to_vec()));
+    println!(
+        "{}",
+        widths
+            .iter()
+            .map(|&w| "-".repeat(w))
+            .collect::<Vec<String>>()
+            .join("  ")
+    );
+    for row in &rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
     }
 }
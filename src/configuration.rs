@@ -2,8 +2,11 @@
 //! care of loading the static configuration instance
 
 use directories::{ProjectDirs, UserDirs};
+use library::QueryParams;
 use model::LibraryEntryMeta;
+use std::collections::HashMap;
 use std::default::Default;
+use std::env;
 use std::fs::{create_dir_all, File};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
@@ -45,6 +48,53 @@ fn get_config_paths() -> Vec<PathBuf> {
     vec![dirs.config_dir().join("config.yaml")]
 }
 
+#[cfg(target_os = "macos")]
+const DEFAULT_VIEWER: &str = "open";
+
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_VIEWER: &str = "xdg-open";
+
+/// Describes how the additional copies of a document in the directories of its tags are
+/// created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkMode {
+    #[default]
+    Hardlink,
+    Symlink,
+    /// Shares the data of the copies if the filesystem supports it, else copies the document
+    #[serde(alias = "reflink-with-fallback")]
+    Reflink,
+    Copy,
+}
+
+/// A directory tree of symbolic links to the documents matching a query.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ViewDefinition {
+    // Path of the links relative to the views directory like `by-year/%Y`, which may contain
+    // the expandos of `name_pattern` and %G for the tags of a document
+    path: String,
+    // Only documents matching the query are part of the view
+    #[serde(default)]
+    query: SavedQuery,
+}
+
+/// Regular expressions and a query expression matched against the fields of documents like the
+/// arguments of `query`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedQuery {
+    author: Option<String>,
+    year: Option<String>,
+    title: Option<String>,
+    #[serde(rename = "type")]
+    doc_type: Option<String>,
+    tag: Option<String>,
+    text: Option<String>,
+    /// A query expression like `author:knuth and not type:misc`
+    expression: Option<String>,
+}
+
 lazy_static! {
     static ref CONFIG_FILE_PATHS: Vec<PathBuf> = get_config_paths();
 }
@@ -73,6 +123,32 @@ pub struct ConfigurationVariables {
     max_author_names: u32,
     author_separator: String,
     move_files: bool,
+    // Command used to open documents, the path of the document is appended as last argument
+    #[serde(default)]
+    viewer: Option<String>,
+    // Viewer commands used instead of `viewer` for specific file extensions
+    #[serde(default)]
+    viewer_overrides: HashMap<String, String>,
+    // Command used to edit bibliographies, overrides the EDITOR environment variable
+    #[serde(default)]
+    editor: Option<String>,
+    // Base URL of the service DOIs are resolved with using content negotiation
+    #[serde(default = "default_resolver_url")]
+    resolver_url: String,
+    // Whether metadata may be looked up online, else only cached records are used
+    #[serde(default = "default_lookup_online")]
+    lookup_online: bool,
+    // Number of threads digests of multiple files are calculated with, 0 for one per processor
+    #[serde(default)]
+    digest_threads: usize,
+    // How documents are linked into the directories of their tags
+    #[serde(default)]
+    tag_link_mode: LinkMode,
+    // Directory the views are created in, defaults to `views` in the document directory
+    #[serde(default)]
+    views_location: Option<PathBuf>,
+    #[serde(default)]
+    views: Vec<ViewDefinition>,
 }
 
 /// Keeps the global configuration
@@ -89,19 +165,36 @@ impl Default for ConfigurationVariables {
             .document_dir()
             .expect("Failed to determine default document directory")
             .join("Papers");
-        let default_library_path = (&default_doc_dir).join("library.json");
-
-        ConfigurationVariables::new(
-            default_doc_dir,
-            default_library_path,
-            String::from("%A-%y-%T"),
-            2,
-            String::from("_"),
-            true,
-        )
+        let default_library_path = default_doc_dir.join("library.json");
+
+        ConfigurationVariables {
+            document_location: default_doc_dir,
+            library_location: default_library_path,
+            name_pattern: String::from("%A-%y-%T"),
+            max_author_names: 2,
+            author_separator: String::from("_"),
+            move_files: true,
+            viewer: None,
+            viewer_overrides: HashMap::new(),
+            editor: None,
+            resolver_url: default_resolver_url(),
+            lookup_online: default_lookup_online(),
+            digest_threads: 0,
+            tag_link_mode: LinkMode::default(),
+            views_location: None,
+            views: Vec::new(),
+        }
     }
 }
 
+fn default_resolver_url() -> String {
+    String::from("https://doi.org")
+}
+
+fn default_lookup_online() -> bool {
+    true
+}
+
 impl ConfigurationVariables {
     pub fn document_location(&self) -> &Path {
         &self.document_location
@@ -127,22 +220,77 @@ impl ConfigurationVariables {
         self.move_files
     }
 
-    pub fn new(
-        document_location: PathBuf,
-        library_location: PathBuf,
-        name_pattern: String,
-        max_author_names: u32,
-        author_separator: String,
-        move_files: bool,
-    ) -> ConfigurationVariables {
-        ConfigurationVariables {
-            document_location,
-            library_location,
-            max_author_names,
-            author_separator,
-            name_pattern,
-            move_files,
-        }
+    pub fn viewer(&self) -> Option<&str> {
+        self.viewer.as_deref()
+    }
+
+    pub fn resolver_url(&self) -> &str {
+        &self.resolver_url
+    }
+
+    pub fn lookup_online(&self) -> bool {
+        self.lookup_online
+    }
+
+    pub fn digest_threads(&self) -> usize {
+        self.digest_threads
+    }
+
+    pub fn tag_link_mode(&self) -> LinkMode {
+        self.tag_link_mode
+    }
+
+    pub fn views_location(&self) -> PathBuf {
+        self.views_location
+            .clone()
+            .unwrap_or_else(|| self.document_location.join("views"))
+    }
+
+    pub fn views(&self) -> &[ViewDefinition] {
+        &self.views
+    }
+
+    /// Determines the command used to edit text files, falling back to the `EDITOR`
+    /// environment variable and finally `vi`.
+    pub fn editor(&self) -> String {
+        self.editor
+            .clone()
+            .or_else(|| env::var("EDITOR").ok().filter(|e| !e.trim().is_empty()))
+            .unwrap_or_else(|| String::from("vi"))
+    }
+
+    /// Determines the command used to view a file with the given extension, falling back to
+    /// the desktop default application if no viewer was configured.
+    pub fn viewer_for(&self, extension: Option<&str>) -> &str {
+        extension
+            .and_then(|ext| self.viewer_overrides.get(&ext.to_lowercase()))
+            .map(String::as_str)
+            .or_else(|| self.viewer())
+            .unwrap_or(DEFAULT_VIEWER)
+    }
+}
+
+impl ViewDefinition {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> &SavedQuery {
+        &self.query
+    }
+}
+
+impl SavedQuery {
+    pub fn params(&self) -> QueryParams<'_> {
+        QueryParams::new(
+            self.author.as_deref(),
+            self.year.as_deref(),
+            self.title.as_deref(),
+            self.doc_type.as_deref(),
+            self.tag.as_deref(),
+            self.text.as_deref(),
+            self.expression.as_deref(),
+        )
     }
 }
 
@@ -191,7 +339,9 @@ impl Configuration {
                 .find(|&p| p.exists())
                 .unwrap_or(&CONFIG_FILE_PATHS[0]),
         );
-        create_dir_all(&path)?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
         serde_yaml::to_writer(File::create(path)?, &self.variables())?;
 
         Ok(())
@@ -200,6 +350,35 @@ impl Configuration {
     pub fn variables(&self) -> &ConfigurationVariables {
         &self.variables
     }
+
+    /// Creates a configuration keeping the documents and the library in `dir`, overriding the
+    /// defaults with the variables given as YAML in `variables`.
+    #[cfg(test)]
+    pub fn for_tests(dir: &Path, variables: &str) -> Configuration {
+        use serde_yaml::{Mapping, Value};
+
+        let defaults = format!(
+            r#"
+            document_location: {0}
+            library_location: {0}/library.json
+            name_pattern: "%A-%y-%T"
+            max_author_names: 2
+            author_separator: "_"
+            move_files: false
+            lookup_online: false
+            "#,
+            dir.display()
+        );
+        let mut merged: Mapping = serde_yaml::from_str(&defaults).unwrap();
+        if !variables.trim().is_empty() {
+            let overrides: Mapping = serde_yaml::from_str(variables).unwrap();
+            for (name, value) in overrides {
+                merged.insert(name, value);
+            }
+        }
+
+        Configuration::new(serde_yaml::from_value(Value::Mapping(merged)).unwrap())
+    }
 }
 
 /// A module providing some helper functions for applying the configuration values
@@ -208,9 +387,9 @@ pub mod util {
 
     fn get_last_name(author: &str) -> Option<&str> {
         if author.contains(',') {
-            author.split(',').nth(0)
+            author.split(',').next()
         } else {
-            author.split(' ').last()
+            author.split(' ').next_back()
         }
     }
 
@@ -219,13 +398,23 @@ pub mod util {
         original_name: &str,
         meta: &LibraryEntryMeta,
         conf: &Configuration,
+    ) -> String {
+        expand_pattern(conf.variables().name_pattern(), original_name, meta, conf)
+    }
+
+    /// Replaces the expandos described in `ConfigurationVariables` in the given pattern
+    pub fn expand_pattern(
+        pattern: &str,
+        original_name: &str,
+        meta: &LibraryEntryMeta,
+        conf: &Configuration,
     ) -> String {
         let (authors, authors_last_name) =
             if !meta.authors().is_empty() && conf.variables().max_author_names() != 0 {
                 meta.authors()
                     .iter()
                     .take(conf.variables().max_author_names() as usize)
-                    .map(|s| sanitize_string(&s))
+                    .map(|s| sanitize_string(s))
                     .map(|s| (s.clone(), String::from(get_last_name(&s).unwrap_or(&s))))
                     .enumerate()
                     .map(|(i, (s1, s2))| {
@@ -248,8 +437,7 @@ pub mod util {
 
         let title = sanitize_string(meta.title());
 
-        conf.variables()
-            .name_pattern()
+        pattern
             .replace("%F", original_name)
             .replace("%f", &original_name.to_lowercase())
             .replace("%K", meta.key())
@@ -267,8 +455,6 @@ pub mod util {
     }
 
     fn sanitize_string(s: &str) -> String {
-        s.chars()
-         .filter(| c | c.is_alphanumeric())
-         .collect()
+        s.chars().filter(|c| c.is_alphanumeric()).collect()
     }
 }
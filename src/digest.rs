@@ -0,0 +1,84 @@
+//! Calculates the digests used to identify the documents of the library.
+
+use model::FileDigest;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::io::{IsTerminal, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// The size of the chunks files are read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Calculates the SHA-256 digest of a file, reading it in chunks so even large files do not
+/// have to be loaded into memory.
+pub fn calculate<P: AsRef<Path>>(path: P) -> io::Result<FileDigest> {
+    let mut file = File::open(path.as_ref())?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => hasher.input(&buffer[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(hasher.result())
+}
+
+/// Calculates the digests of all files using up to `threads` threads, or one thread per
+/// processor if `threads` is 0. The results are in the order of the given paths.
+///
+/// If `label` is given and standard error is a terminal, the progress is shown using it.
+pub fn calculate_all<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    threads: usize,
+    label: Option<&str>,
+) -> Vec<io::Result<FileDigest>> {
+    let threads = if threads == 0 {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        threads
+    };
+    let threads = threads.min(paths.len()).max(1);
+    let progress = label.filter(|_| paths.len() > 1 && io::stderr().is_terminal());
+
+    let next = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<io::Result<FileDigest>>>> =
+        Mutex::new((0..paths.len()).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= paths.len() {
+                    break;
+                }
+                let digest = calculate(&paths[i]);
+                if let Ok(mut results) = results.lock() {
+                    results[i] = Some(digest);
+                }
+                let done = finished.fetch_add(1, Ordering::SeqCst) + 1;
+                if let Some(label) = progress {
+                    eprint!("\r{} {}/{}", label, done, paths.len());
+                    io::stderr().flush().ok();
+                }
+            });
+        }
+    });
+    if progress.is_some() {
+        eprintln!();
+    }
+
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(io::Error::other("Digest not calculated"))))
+        .collect()
+}
@@ -0,0 +1,35 @@
+//! Provides functions for letting the user edit text in an external text editor.
+
+use configuration::Configuration;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::process::Command;
+use tempfile::Builder;
+
+/// Opens the configured editor on a temporary file with the given suffix containing `content`
+/// and returns the content of the file after the editor was closed.
+pub fn edit(content: &str, suffix: &str, conf: &Configuration) -> io::Result<String> {
+    let mut file = Builder::new().prefix("reed-").suffix(suffix).tempfile()?;
+    file.write_all(content.as_bytes())?;
+    file.flush()?;
+
+    // The editor command may contain additional arguments like `code --wait`
+    let editor = conf.variables().editor();
+    let mut editor_args = editor.split_whitespace();
+    let program = editor_args
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No editor configured"))?;
+    let status = Command::new(program)
+        .args(editor_args)
+        .arg(file.path())
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Editor {} exited with {}",
+            program, status
+        )));
+    }
+
+    fs::read_to_string(file.path())
+}
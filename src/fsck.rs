@@ -0,0 +1,423 @@
+//! Verifies that the documents in the document directory match the library and relocates
+//! documents which were moved by hand.
+
+use batch;
+use configuration::{Configuration, LinkMode};
+use digest;
+use library::Library;
+use model::{FileDigest, LibraryEntry};
+use resolve;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use storage;
+
+/// The kinds of inconsistencies between the library and the document directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IssueKind {
+    /// A file of an entry does not exist
+    Missing,
+    /// A file of an entry could not be read
+    Unreadable(String),
+    /// The content of a file does not match the digest stored in the library
+    DigestMismatch,
+    /// A tagged copy of a document is not linked according to the configured link mode
+    BrokenLink,
+    /// A file in the document directory is not referenced by any entry
+    Orphan,
+}
+
+/// An inconsistency found while verifying the library.
+#[derive(Debug, Clone)]
+pub struct Issue {
+    /// The key of the affected entry, `None` for orphans
+    pub key: Option<String>,
+    pub path: PathBuf,
+    pub kind: IssueKind,
+    /// An intact copy of the document the file can be restored from
+    pub source: Option<PathBuf>,
+    pub digest: Option<FileDigest>,
+}
+
+/// A stale file path of an entry replaced by the path the document was found at.
+#[derive(Debug, Clone)]
+pub struct Relink {
+    pub key: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IssueKind::Missing => f.write_str("missing"),
+            IssueKind::Unreadable(err) => write!(f, "unreadable ({})", err),
+            IssueKind::DigestMismatch => f.write_str("digest mismatch"),
+            IssueKind::BrokenLink => f.write_str("not linked according to tag_link_mode"),
+            IssueKind::Orphan => f.write_str("not in library"),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key {
+            Some(ref key) => write!(f, "{}: {} ({})", self.path.display(), self.kind, key),
+            None => write!(f, "{}: {}", self.path.display(), self.kind),
+        }
+    }
+}
+
+impl Issue {
+    /// Whether the issue can be fixed without losing data, i.e. by restoring the file from an
+    /// intact copy.
+    pub fn fixable(&self) -> bool {
+        self.source.is_some()
+            && (self.kind == IssueKind::Missing || self.kind == IssueKind::BrokenLink)
+    }
+
+    /// Restores the file by linking it to an intact copy using the given mode. The link is
+    /// created next to the file first so the file is replaced atomically.
+    pub fn fix(&self, mode: LinkMode) -> io::Result<()> {
+        let (source, digest) = match (&self.source, &self.digest) {
+            (Some(s), Some(d)) if self.fixable() => (s.canonicalize()?, d),
+            _ => {
+                return Err(io::Error::other(format!(
+                    "{} can not be fixed safely",
+                    self.path.display()
+                )))
+            }
+        };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".reed-fsck");
+        let temp = PathBuf::from(temp);
+        storage::link(&source, &temp, mode, digest)?;
+        fs::rename(&temp, &self.path).inspect_err(|_| {
+            fs::remove_file(&temp).ok();
+        })
+    }
+}
+
+/// Identifies the file a path refers to in order to detect hard links. Symbolic links are not
+/// followed.
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Checks whether an intact copy of a document was created from `source` using the given mode.
+fn linked_as(path: &Path, source: &Path, mode: LinkMode) -> bool {
+    let is_symlink = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    let source = match source.canonicalize() {
+        Ok(s) => s,
+        Err(_) => return true,
+    };
+    let source_id = file_id(&source);
+    match mode {
+        LinkMode::Hardlink => !is_symlink && (source_id.is_none() || file_id(path) == source_id),
+        LinkMode::Symlink => is_symlink && path.canonicalize().ok() == Some(source),
+        LinkMode::Reflink | LinkMode::Copy => {
+            !is_symlink && (source_id.is_none() || file_id(path) != source_id)
+        }
+    }
+}
+
+/// Re-hashes every file of every entry and searches the document directory for files not
+/// referenced by the library.
+pub fn verify(lib: &Library, conf: &Configuration) -> io::Result<Vec<Issue>> {
+    let mut issues = Vec::new();
+
+    let existing: Vec<&str> = lib
+        .entries()
+        .iter()
+        .flat_map(|e| e.file_paths().iter().map(String::as_str))
+        .filter(|p| Path::new(p).exists())
+        .collect();
+    let digests = digest::calculate_all(
+        &existing,
+        conf.variables().digest_threads(),
+        Some("Verifying"),
+    );
+    let digests: HashMap<&str, Result<FileDigest, String>> = existing
+        .into_iter()
+        .zip(digests.into_iter().map(|d| d.map_err(|e| e.to_string())))
+        .collect();
+
+    for entry in lib.entries() {
+        let key = Some(String::from(entry.meta().key()));
+        let mut intact: Vec<PathBuf> = Vec::new();
+        let mut damaged: Vec<(PathBuf, IssueKind)> = Vec::new();
+        for p in entry.file_paths() {
+            let path = PathBuf::from(p);
+            match digests.get(p.as_str()) {
+                None => damaged.push((path, IssueKind::Missing)),
+                Some(Ok(d)) if d == entry.digest() => intact.push(path),
+                Some(Ok(_)) => damaged.push((path, IssueKind::DigestMismatch)),
+                Some(Err(e)) => damaged.push((path, IssueKind::Unreadable(e.clone()))),
+            }
+        }
+
+        let source = intact.first().cloned();
+        if let Some(ref source) = source {
+            let mode = conf.variables().tag_link_mode();
+            for path in intact.iter().skip(1) {
+                if !linked_as(path, source, mode) {
+                    damaged.push((path.clone(), IssueKind::BrokenLink));
+                }
+            }
+        }
+        for (path, kind) in damaged {
+            issues.push(Issue {
+                key: key.clone(),
+                path,
+                kind,
+                source: source.clone(),
+                digest: Some(*entry.digest()),
+            });
+        }
+    }
+
+    let referenced: HashSet<PathBuf> = lib
+        .entries()
+        .iter()
+        .flat_map(|e| e.file_paths().iter().map(PathBuf::from))
+        .collect();
+    let vars = conf.variables();
+    let ignored = [
+        vars.library_location().to_path_buf(),
+        resolve::cache_directory(conf),
+        vars.views_location(),
+    ];
+    let mut orphans = Vec::new();
+    let mut pending = vec![vars.document_location().to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        if !dir.is_dir() {
+            continue;
+        }
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+            if hidden || ignored.contains(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if !referenced.contains(&path) {
+                orphans.push(Issue {
+                    key: None,
+                    path,
+                    kind: IssueKind::Orphan,
+                    source: None,
+                    digest: None,
+                });
+            }
+        }
+    }
+
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+    issues.append(&mut orphans);
+
+    Ok(issues)
+}
+
+/// Searches the given directories for the documents of entries whose files do not exist
+/// anymore and updates their paths. Documents are recognized by their digest, so they may have
+/// been renamed as well. Paths for which no document was found are left untouched.
+pub fn relink(
+    lib: &mut Library,
+    dirs: &[PathBuf],
+    conf: &Configuration,
+) -> io::Result<Vec<Relink>> {
+    let stale: Vec<(usize, usize)> = lib
+        .entries()
+        .iter()
+        .enumerate()
+        .flat_map(|(i, e)| {
+            e.file_paths()
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| !Path::new(p).exists())
+                .map(move |(j, _)| (i, j))
+        })
+        .collect();
+    if stale.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Only files not yet known to the library are candidates
+    let referenced: HashSet<&str> = lib
+        .entries()
+        .iter()
+        .flat_map(|e| e.file_paths().iter().map(String::as_str))
+        .collect();
+    // Symbolic links like those of tagged copies and views are no documents of their own and
+    // may be left dangling by the move
+    let views = conf.variables().views_location();
+    let mut paths: Vec<String> = Vec::new();
+    for dir in dirs {
+        for path in batch::list_documents(dir)? {
+            let is_symlink = fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+            if is_symlink || path.starts_with(&views) {
+                continue;
+            }
+            let path = match path.canonicalize() {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            match path.to_str() {
+                Some(p) if !referenced.contains(p) && !paths.iter().any(|c| c == p) => {
+                    paths.push(String::from(p))
+                }
+                _ => continue,
+            }
+        }
+    }
+    let digests = digest::calculate_all(&paths, conf.variables().digest_threads(), Some("Hashing"));
+    let mut candidates: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
+    for (path, digest) in paths.into_iter().zip(digests) {
+        if let Ok(digest) = digest {
+            candidates.entry(digest.to_vec()).or_default().push(path);
+        }
+    }
+
+    let mut relinked = Vec::new();
+    let mut updates: Vec<(usize, LibraryEntry)> = Vec::new();
+    for (i, j) in stale {
+        let entry = lib.entries()[i].clone();
+        let new = match candidates.get_mut(entry.digest().as_slice()) {
+            Some(found) if !found.is_empty() => found.remove(0),
+            _ => continue,
+        };
+        match updates.last_mut() {
+            Some((k, updated)) if *k == i => updated.set_file_path(j, new.clone()),
+            _ => {
+                let mut updated = entry.clone();
+                updated.set_file_path(j, new.clone());
+                updates.push((i, updated));
+            }
+        }
+        relinked.push(Relink {
+            key: String::from(entry.meta().key()),
+            old: entry.file_paths()[j].clone(),
+            new,
+        });
+    }
+    if updates.is_empty() {
+        return Ok(relinked);
+    }
+    let updated: Vec<usize> = updates.iter().map(|(i, _)| *i).collect();
+    lib.replace_entries_stored(updates)?;
+
+    // Symbolic links among the copies still point to the old location of the first copy
+    for i in updated {
+        let paths = lib.entries()[i].file_paths();
+        if paths.first().is_some_and(|p| Path::new(p).exists()) {
+            storage::update_symlinks(paths)?;
+        }
+    }
+
+    Ok(relinked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import::import_for_tests as import;
+
+    /// Describes the issues like `a/paper.pdf Missing` with paths relative to the document
+    /// directory.
+    fn issues(lib: &Library, conf: &Configuration) -> Vec<String> {
+        let root = conf.variables().document_location();
+        let relative = |p: &Path| p.strip_prefix(root).unwrap().display().to_string();
+        verify(lib, conf)
+            .unwrap()
+            .iter()
+            .map(|i| format!("{} {:?}", relative(&i.path), i.kind))
+            .collect()
+    }
+
+    #[test]
+    fn verify_detects_missing_damaged_and_orphaned_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "tag_link_mode: copy");
+        let mut lib = Library::new(conf.variables().library_location());
+        import(&mut lib, "X", &["a", "b"], &conf);
+        import(&mut lib, "Y", &[], &conf);
+        assert!(issues(&lib, &conf).is_empty());
+
+        fs::remove_file(&lib.entries()[1].file_paths()[0]).unwrap();
+        fs::write(&lib.entries()[0].file_paths()[1], "changed").unwrap();
+        fs::write(dir.path().join("a/stray.pdf"), "").unwrap();
+        assert_eq!(
+            issues(&lib, &conf),
+            [
+                "b/DoeJohn-20-X.pdf DigestMismatch",
+                "DoeJohn-20-Y.pdf Missing",
+                "a/stray.pdf Orphan",
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_ignores_the_library_the_cache_and_the_views() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "");
+        let mut lib = Library::new(conf.variables().library_location());
+        import(&mut lib, "X", &[], &conf);
+        assert!(conf.variables().library_location().is_file());
+
+        let cache = resolve::cache_directory(&conf);
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("doi-10.1000-xyz.bib"), "").unwrap();
+        let views = conf.variables().views_location();
+        fs::create_dir_all(views.join("all")).unwrap();
+        fs::write(views.join("all/DoeJohn-20-X.pdf"), "").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        assert!(issues(&lib, &conf).is_empty());
+
+        fs::write(dir.path().join("stray.pdf"), "").unwrap();
+        assert_eq!(issues(&lib, &conf), ["stray.pdf Orphan"]);
+    }
+
+    #[test]
+    fn fix_restores_missing_copies_with_the_link_mode() {
+        let modes = [
+            ("hardlink", LinkMode::Hardlink),
+            ("symlink", LinkMode::Symlink),
+            ("reflink", LinkMode::Reflink),
+            ("copy", LinkMode::Copy),
+        ];
+        for &(name, mode) in &modes {
+            let dir = tempfile::tempdir().unwrap();
+            let conf = Configuration::for_tests(dir.path(), &format!("tag_link_mode: {}", name));
+            let mut lib = Library::new(conf.variables().library_location());
+            import(&mut lib, "X", &["a", "b"], &conf);
+            fs::remove_file(&lib.entries()[0].file_paths()[1]).unwrap();
+
+            let found = verify(&lib, &conf).unwrap();
+            assert_eq!(found.len(), 1, "{}", name);
+            let issue = &found[0];
+            assert_eq!(issue.kind, IssueKind::Missing);
+            assert!(issue.fixable());
+            issue.fix(mode).unwrap();
+
+            let source = issue.source.as_ref().unwrap();
+            assert!(linked_as(&issue.path, source, mode), "{}", name);
+            assert_eq!(fs::read_to_string(&issue.path).unwrap(), "X");
+            assert!(issues(&lib, &conf).is_empty(), "{}", name);
+        }
+    }
+}
@@ -2,18 +2,30 @@
 
 use configuration::util::assemble_name;
 use configuration::Configuration;
-use model::{FileDigest, LibraryEntry, LibraryEntryMeta, LibraryEntryType, Month, ParseMonthError, TagMap};
-use sha2::{Digest, Sha256};
+use digest;
+use editor;
+use library::{Duplicate, Library, LibraryPersistenceError};
+use model::{
+    FileDigest, Identifier, LibraryEntry, LibraryEntryMeta, LibraryEntryType, Month,
+    ParseMonthError, TagMap,
+};
+use resolve;
+use resolve::{ResolveError, Resolver};
 use std::convert::From;
-use std::error::Error;
-use std::fs;
+use std::env;
 use std::fs::File;
 use std::io;
 use std::io::copy;
 use std::io::BufReader;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
 use std::string;
 use std::vec::Vec;
+use storage;
+use storage::Transaction;
+use tags;
 
 quick_error! {
     #[derive(Debug)]
@@ -45,43 +57,271 @@ quick_error! {
         CorruptFilePath(descr: String) {
             display(self_) -> ("File path corrupt: {}", descr)
         }
+        /// Returned when the document or its metadata is already part of the library
+        Duplicate(existing: Box<LibraryEntry>, reason: Duplicate) {
+            display(self_) -> ("Document already in the library as {} ({})",
+                               existing.meta().key(), reason)
+        }
+        /// Returned when the library could not be stored after importing
+        Library(err: LibraryPersistenceError) {
+            display(self_) -> ("Storing the library failed: {}", err)
+            from()
+        }
+        /// Returned when looking up a bibliography online failed
+        Lookup(err: ResolveError) {
+            display(self_) -> ("Lookup failed: {}", err)
+            from()
+        }
+    }
+}
+
+pub type ImportResultSet = Vec<LibraryEntryMeta>;
+pub type ImportResult = Result<ImportResultSet, ImportError>;
+
+/// The formats bibliographies can be imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BibliographyFormat {
+    BibTeX,
+    Ris,
+    CslJson,
+}
+
+/// The sources a bibliography can be read from.
+#[derive(Debug, Clone, Copy)]
+pub enum BibliographySource<'a> {
+    File(&'a Path),
+    Stdin,
+    Clipboard,
+    /// Reads the metadata embedded in the imported document itself, letting the user complete
+    /// it in a text editor if necessary
+    Document(&'a Path),
+    /// Looks up the bibliography of the document with the given identifier
+    Identifier(&'a Identifier),
+}
+
+/// Options controlling how a document is imported into the library.
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    /// Move the document regardless of the configuration
+    pub force_move: bool,
+    /// Copy the document regardless of the configuration
+    pub force_copy: bool,
+    /// Import documents already in the library instead of refusing them
+    pub allow_duplicate: bool,
+    /// The tags the document is categorized with
+    pub tags: Vec<String>,
+}
+
+/// The comment the editor is opened with when asking for a bibliography.
+const BIBLIOGRAPHY_HEADER: &str = "\
+% Enter the BibTeX bibliography of the imported document below.
+% Lines starting with % are ignored; save an empty file to abort the import.
+%
+";
+
+/// The example shown in the editor if no metadata about the document is known.
+const BIBLIOGRAPHY_EXAMPLE: &str = "\
+% @article{citationkey,
+%     author = {Last, First and Last, First},
+%     title = {Title},
+%     year = {2019},
+% }
+
+";
+
+impl BibliographyFormat {
+    /// Determines the format of a bibliography file from its extension.
+    pub fn from_extension(ext: &str) -> Option<BibliographyFormat> {
+        match ext.to_lowercase().as_str() {
+            "bib" => Some(BibliographyFormat::BibTeX),
+            "ris" => Some(BibliographyFormat::Ris),
+            "json" => Some(BibliographyFormat::CslJson),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format of a bibliography from its content.
+    pub fn detect(content: &str) -> Option<BibliographyFormat> {
+        let content = content.trim_start_matches('\u{feff}').trim_start();
+        if content.starts_with("TY  -") {
+            Some(BibliographyFormat::Ris)
+        } else if content.starts_with('[') || content.starts_with('{') {
+            Some(BibliographyFormat::CslJson)
+        } else if content.starts_with('@') || content.contains("\n@") {
+            Some(BibliographyFormat::BibTeX)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for BibliographyFormat {
+    type Err = ImportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bibtex" | "bib" => Ok(BibliographyFormat::BibTeX),
+            "ris" => Ok(BibliographyFormat::Ris),
+            "csl-json" | "csl" | "json" => Ok(BibliographyFormat::CslJson),
+            _ => Err(ImportError::UnknownFile(format!(
+                "Bibliography format {} not known.",
+                s
+            ))),
+        }
+    }
+}
+
+/// Reads a bibliography from the given source and imports all of its entries. If no format
+/// is given, it is determined from the file extension or else guessed from the content.
+pub fn load_bibliography(
+    source: BibliographySource,
+    format: Option<BibliographyFormat>,
+    conf: &Configuration,
+) -> ImportResult {
+    let content = match source {
+        BibliographySource::File(path) => read_file(path)?,
+        BibliographySource::Stdin => {
+            let mut bytes: Vec<u8> = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            String::from_utf8(bytes)?
+        }
+        BibliographySource::Clipboard => read_clipboard()?,
+        BibliographySource::Document(path) => return import_document(path, conf),
+        BibliographySource::Identifier(id) => {
+            let resolver = resolve::from_cfg(conf);
+            return lookup(std::slice::from_ref(id), &resolver, |_| true).ok_or_else(|| {
+                ImportError::NoBibliographyFound(format!("Failed to look up {}", id))
+            });
+        }
+    };
+
+    let format = format
+        .or_else(|| match source {
+            BibliographySource::File(path) => path
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(BibliographyFormat::from_extension),
+            _ => None,
+        })
+        .or_else(|| BibliographyFormat::detect(&content))
+        .ok_or_else(|| {
+            ImportError::UnknownFile(String::from(
+                "Could not determine the bibliography format; please specify it.",
+            ))
+        })?;
+
+    import_str(content, format)
+}
+
+/// Imports all entries of a bibliography given in the specified format.
+pub fn import_str(content: String, format: BibliographyFormat) -> ImportResult {
+    match format {
+        BibliographyFormat::BibTeX => bib::import(content),
+        BibliographyFormat::Ris => ris::import(content),
+        BibliographyFormat::CslJson => csl::import(content),
     }
 }
 
-type ImportResultSet = Vec<LibraryEntryMeta>;
-type ImportResult = Result<ImportResultSet, ImportError>;
+/// Splits a string at all occurrences of `separator` which are not escaped by a backslash.
+fn split_unescaped(s: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    if let Some(part) = parts.last_mut() {
+                        part.push(next);
+                    }
+                }
+            }
+            c if c == separator => parts.push(String::new()),
+            c => {
+                if let Some(part) = parts.last_mut() {
+                    part.push(c);
+                }
+            }
+        }
+    }
+    parts
+}
+
+/// Parses the paths of the documents referenced in the `file` tag of an entry as written by
+/// JabRef (`description:path:type`), Zotero and Mendeley. Relative paths are resolved relative
+/// to `base`.
+pub fn file_field_paths(meta: &LibraryEntryMeta, base: &Path) -> Vec<PathBuf> {
+    let field = match meta
+        .original_tags()
+        .and_then(|t| t.iter().find(|(name, _)| name.to_lowercase() == "file"))
+    {
+        Some((_, field)) => field,
+        None => return Vec::new(),
+    };
+
+    split_unescaped(field, ';')
+        .iter()
+        .filter_map(|link| {
+            let parts = split_unescaped(link, ':');
+            let path = match parts.len() {
+                0 => return None,
+                1 | 2 => parts[0].clone(),
+                // Windows drive letters may contain unescaped colons as well
+                n => parts[1..n - 1].join(":"),
+            };
+            let path = path.trim();
+            if path.is_empty() {
+                return None;
+            }
+
+            let path = PathBuf::from(path);
+            if path.is_absolute() {
+                return Some(path);
+            }
+            let relative = base.join(&path);
+            // Mendeley omits the leading slash of absolute paths
+            let absolute = Path::new("/").join(&path);
+            if !relative.exists() && absolute.exists() {
+                Some(absolute)
+            } else {
+                Some(relative)
+            }
+        })
+        .collect()
+}
 
+/// Imports a file into the library using the metadata of the bibliography entry with the given
+/// key or the only entry if no key is given and returns the new entry.
+///
+/// Documents whose file, citation key or identifiers are already part of the library are
+/// refused unless duplicates are allowed. In that case an identical file is not imported again,
+/// but the new tags are added to the existing entry instead.
 pub fn import<P: AsRef<Path>>(
     file_path: P,
-    resource_path: P,
+    results: ImportResultSet,
     key: Option<&str>,
-    force_move: bool,
-    force_copy: bool,
-    tags: Vec<String>,
+    options: &ImportOptions,
+    lib: &mut Library,
     conf: &Configuration,
 ) -> Result<LibraryEntry, ImportError> {
-    // Read file data as UTF-8 String
-    let mut resource_reader = BufReader::new(File::open(&resource_path)?);
-    let mut resource_bytes: Vec<u8> = Vec::new();
-    copy(&mut resource_reader, &mut resource_bytes)?;
-    let file_content = String::from_utf8(resource_bytes)?;
-
-    // Use fitting import function to import the file
-    let results = match resource_path.as_ref().extension() {
-        Some(ext) => {
-            if ext == "bib" {
-                bib::import(file_content)
-            } else {
-                Err(ImportError::UnknownFile(format!(
-                    "File extension {} not known.",
-                    ext.to_string_lossy()
-                )))
-            }
-        }
-        None => Err(ImportError::UnknownFile(String::from(
-            "File has no extension.",
-        ))),
-    }?;
+    let digest = digest::calculate(&file_path)?;
+    import_digested(file_path, digest, results, key, options, lib, conf)
+}
+
+/// Imports a file like `import` whose digest was already calculated.
+pub fn import_digested<P: AsRef<Path>>(
+    file_path: P,
+    digest: FileDigest,
+    results: ImportResultSet,
+    key: Option<&str>,
+    options: &ImportOptions,
+    lib: &mut Library,
+    conf: &Configuration,
+) -> Result<LibraryEntry, ImportError> {
+    let tags = options
+        .tags
+        .iter()
+        .map(|t| tags::normalize_new(t, conf))
+        .collect::<io::Result<Vec<String>>>()?;
 
     let known_keys = || results.iter().map(|bib| bib.key()).collect::<Vec<&str>>();
 
@@ -99,7 +339,7 @@ pub fn import<P: AsRef<Path>>(
             }),
         None => {
             if results.len() == 1 {
-                Ok((&results[0]).clone())
+                Ok(results[0].clone())
             } else {
                 Err(ImportError::NoBibliographyFound(format!(
                     "Multiple bibliographies in file. Please specify a key. \
@@ -110,6 +350,20 @@ pub fn import<P: AsRef<Path>>(
         }
     }?;
 
+    // Generated keys must not be mistaken for the key of another document
+    let mut meta = meta;
+    if meta.has_generated_key() {
+        let key = lib.unique_key(meta.key());
+        meta.set_generated_key(key);
+    }
+
+    // Identifiers printed in the document can be used for lookups and detecting duplicates
+    if meta.identifiers().is_empty() {
+        for id in ident::scan_document(file_path.as_ref(), None).own {
+            meta.add_identifier(id);
+        }
+    }
+
     // Decompose the file name
     let file_stem = file_path
         .as_ref()
@@ -134,56 +388,383 @@ pub fn import<P: AsRef<Path>>(
             ))
         })?;
 
-    // New lifetime to make sure the reader is closed before moving any file
-    let digest = calculate_digest(&file_path)?;
+    let document_location = conf.variables().document_location();
+    match lib.find_duplicate(&meta, &digest) {
+        Some((i, Duplicate::Digest)) if options.allow_duplicate => {
+            return merge_tags(lib, i, &tags, conf)
+        }
+        Some((i, reason)) if !options.allow_duplicate => {
+            let existing = lib.entries()[i].clone();
+            return Err(ImportError::Duplicate(Box::new(existing), reason));
+        }
+        _ => (),
+    }
 
-    let name = format!("{}.{}", assemble_name(file_stem, &meta, conf), file_ext);
-    let paths = if tags.is_empty() {
-        vec![conf
-            .variables()
-            .document_location()
-            .join(&name)
-            .to_str()
-            .map(String::from)
-            .ok_or_else(|| ImportError::CorruptFilePath(String::from("Path is not valid UTF-8")))?]
+    // Other versions of a document are imported as entries of their own with a distinct key
+    let key = lib.unique_key(meta.key());
+    if key != meta.key() {
+        eprintln!(
+            "Warning: Citation key {} already in use; importing as {}.",
+            meta.key(),
+            key
+        );
+        meta.set_generated_key(key);
+    }
+
+    let dirs: Vec<PathBuf> = if tags.is_empty() {
+        vec![document_location.to_path_buf()]
     } else {
-        (&tags)
-            .iter()
-            .map(|t| conf.variables().document_location().join(t).join(&name))
-            .map(|p| p.to_str().map(String::from))
-            .collect::<Option<Vec<String>>>()
-            .ok_or_else(|| ImportError::CorruptFilePath(String::from("Path is not valid UTF-8")))?
+        tags.iter().map(|t| tags::directory(t, conf)).collect()
     };
 
-    for (i, p) in (&paths).iter().enumerate() {
-        let dir = Path::new(&p);
-        if !dir.exists() {
-            fs::create_dir_all(dir)?;
+    // Never overwrite documents already in the library like further attachments of an entry
+    let base_name = assemble_name(file_stem, &meta, conf);
+    let name = storage::free_name(&dirs, &format!("{}.{}", base_name, file_ext));
+    let paths = dirs
+        .iter()
+        .map(|d| d.join(&name).to_str().map(String::from))
+        .collect::<Option<Vec<String>>>()
+        .ok_or_else(|| ImportError::CorruptFilePath(String::from("Path is not valid UTF-8")))?;
+
+    // All file operations are undone if a later step or storing the library fails
+    let mut transaction = Transaction::new();
+    for (i, p) in paths.iter().enumerate() {
+        let p = Path::new(p);
+        if let Some(dir) = p.parent() {
+            transaction.create_dir_all(dir)?;
         }
         if i == 0 {
-            if force_move || (!force_copy && conf.variables().move_files()) {
-                fs::rename(&file_path, p)?;
+            if options.force_move || (!options.force_copy && conf.variables().move_files()) {
+                transaction.move_file(file_path.as_ref(), p, &digest)?;
             } else {
-                fs::copy(&file_path, p)?;
+                transaction.copy_file(file_path.as_ref(), p, &digest)?;
             }
         } else {
-            fs::hard_link(&paths[0], p)?;
+            let mode = conf.variables().tag_link_mode();
+            transaction.link(Path::new(&paths[0]), p, mode, &digest)?;
+        }
+    }
+
+    let entry = LibraryEntry::new(meta, tags, paths, digest);
+    lib.add_entry_stored(entry.clone())?;
+    transaction.commit();
+
+    Ok(entry)
+}
+
+/// Imports a made up document with the given title and tags, whose content is the title.
+/// Returns the index of the new entry.
+#[cfg(test)]
+pub fn import_for_tests(
+    lib: &mut Library,
+    title: &str,
+    tags: &[&str],
+    conf: &Configuration,
+) -> usize {
+    let source_dir = tempfile::tempdir().unwrap();
+    let source = source_dir.path().join("paper.pdf");
+    std::fs::write(&source, title).unwrap();
+    let digest = digest::calculate(&source).unwrap();
+    let meta = LibraryEntryMeta::new(
+        title.to_lowercase(),
+        LibraryEntryType::Article,
+        String::from(title),
+        vec![String::from("Doe, John")],
+        2020,
+        None,
+        None,
+    );
+    let options = ImportOptions {
+        tags: tags.iter().map(|t| String::from(*t)).collect(),
+        ..ImportOptions::default()
+    };
+    import_digested(&source, digest, vec![meta], None, &options, lib, conf).unwrap();
+    lib.entries().len() - 1
+}
+
+/// Adds the tags not yet present to the entry with the given index by linking its document
+/// into the directories of the tags.
+fn merge_tags(
+    lib: &mut Library,
+    index: usize,
+    tags: &[String],
+    conf: &Configuration,
+) -> Result<LibraryEntry, ImportError> {
+    let mut entry = lib.entries()[index].clone();
+    eprintln!(
+        "Warning: Identical document already imported as {}; merging tags.",
+        entry.meta().key()
+    );
+    let source = entry
+        .file_paths()
+        .first()
+        .cloned()
+        .ok_or_else(|| ImportError::CorruptFilePath(String::from("Entry has no file")))?;
+    let name = Path::new(&source)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(String::from)
+        .ok_or_else(|| ImportError::CorruptFilePath(format!("{} has no file name", source)))?;
+
+    let mut transaction = Transaction::new();
+    for tag in tags {
+        if entry.tags().contains(tag) {
+            continue;
+        }
+        let dir = tags::directory(tag, conf);
+        transaction.create_dir_all(&dir)?;
+
+        // A file left in the directory of the tag is only reused if it is the same document
+        let existing = dir.join(&name);
+        let path = if digest::calculate(&existing).ok().as_ref() == Some(entry.digest()) {
+            existing
+        } else {
+            let path = dir.join(storage::free_name(&[&dir], &name));
+            let mode = conf.variables().tag_link_mode();
+            transaction.link(Path::new(&source), &path, mode, entry.digest())?;
+            path
+        };
+        let path_str = path
+            .to_str()
+            .map(String::from)
+            .ok_or_else(|| ImportError::CorruptFilePath(String::from("Path is not valid UTF-8")))?;
+        entry.add_tag(tag.clone(), path_str);
+    }
+    lib.replace_entry_stored(index, entry.clone())?;
+    transaction.commit();
+
+    Ok(entry)
+}
+
+/// Reads a file as UTF-8 string.
+fn read_file<P: AsRef<Path>>(path: P) -> Result<String, ImportError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut bytes: Vec<u8> = Vec::new();
+    copy(&mut reader, &mut bytes)?;
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Reads the content of the clipboard using the clipboard tool of the platform.
+fn read_clipboard() -> Result<String, ImportError> {
+    let candidates: Vec<(&str, Vec<&str>)> = if cfg!(target_os = "macos") {
+        vec![("pbpaste", vec![])]
+    } else if cfg!(windows) {
+        vec![(
+            "powershell",
+            vec!["-NoProfile", "-Command", "Get-Clipboard"],
+        )]
+    } else {
+        let mut c = vec![
+            ("xclip", vec!["-o", "-selection", "clipboard"]),
+            ("xsel", vec!["--clipboard", "--output"]),
+        ];
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            c.insert(0, ("wl-paste", vec!["--no-newline"]));
+        }
+        c
+    };
+
+    for (program, args) in candidates {
+        match Command::new(program).args(&args).output() {
+            Ok(ref output) if output.status.success() => {
+                return Ok(String::from_utf8(output.stdout.clone())?);
+            }
+            _ => continue,
+        }
+    }
+
+    Err(ImportError::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No working clipboard tool found",
+    )))
+}
+
+/// Builds the metadata of a document by looking up the identifiers found in the document. If
+/// this fails, the metadata embedded in the document is used and the user is asked to complete
+/// missing fields in a text editor.
+fn import_document(path: &Path, conf: &Configuration) -> ImportResult {
+    let doc = if is_pdf(path) {
+        match pdf::load(path) {
+            Ok(doc) => Some(doc),
+            Err(e) => {
+                eprintln!("Warning: Failed to read document metadata: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let metadata = doc.as_ref().map(pdf::read_metadata);
+    let scan = ident::scan_document(path, doc.as_ref());
+
+    let mut identifiers = metadata
+        .as_ref()
+        .map(|m| m.identifiers())
+        .unwrap_or_default();
+    for id in scan.own {
+        if !identifiers.contains(&id) {
+            identifiers.push(id);
+        }
+    }
+    let resolver = resolve::from_cfg(conf);
+    if let Some(results) = lookup(&identifiers, &resolver, |_| true) {
+        return Ok(results);
+    }
+    // Other identifiers may belong to cited works, so the record has to match the document
+    let text = normalize_text(&scan.text);
+    let in_text = |meta: &LibraryEntryMeta| {
+        let title = normalize_text(meta.title());
+        !title.is_empty() && text.contains(&title)
+    };
+    if let Some(results) = lookup(&scan.candidates, &resolver, in_text) {
+        return Ok(results);
+    }
+
+    let mut results = match metadata.as_ref().map(|m| (m, m.to_meta())) {
+        Some((_, Ok(meta))) => vec![meta],
+        Some((m, Err(ImportError::Parse(descr)))) => {
+            let mut results = edit_bibliography(error_comment(&descr) + &m.to_bibtex(), conf)?;
+            // Keys left as generated are generated again from the completed metadata
+            for meta in results.iter_mut().filter(|r| r.key() == m.generated_key()) {
+                let key = generate_key(meta.authors(), meta.year());
+                meta.set_generated_key(key);
+            }
+            results
+        }
+        Some((_, Err(e))) => return Err(e),
+        None => edit_bibliography(String::from(BIBLIOGRAPHY_EXAMPLE), conf)?,
+    };
+    for meta in &mut results {
+        for id in &identifiers {
+            meta.add_identifier(id.clone());
+        }
+    }
+
+    Ok(results)
+}
+
+/// Whether a file is a PDF document judging by its extension.
+fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.to_string_lossy().to_lowercase() == "pdf")
+}
+
+/// Reduces a text to its alphanumeric characters in lower case, so texts can be compared
+/// regardless of line breaks and punctuation.
+fn normalize_text(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Looks up the bibliography of the first of the identifiers known to the resolver whose
+/// record is accepted by `accept`.
+fn lookup<R: Resolver, F: Fn(&LibraryEntryMeta) -> bool>(
+    identifiers: &[Identifier],
+    resolver: &R,
+    accept: F,
+) -> Option<ImportResultSet> {
+    for id in identifiers {
+        let record = match resolver.resolve(id) {
+            Ok(r) => r,
+            Err(ResolveError::Unsupported(_)) => continue,
+            Err(e) => {
+                eprintln!("Warning: Failed to look up {}: {}", id, e);
+                continue;
+            }
+        };
+        let mut results = match bib::import(record.clone()) {
+            Ok(ref results) if results.is_empty() => {
+                eprintln!("Warning: Record of {} contains no entry", id);
+                continue;
+            }
+            Ok(results) => results,
+            Err(e) => {
+                eprintln!("Warning: Failed to parse record of {}: {}", id, e);
+                continue;
+            }
+        };
+        if let Err(e) = resolver.accept(id, &record) {
+            eprintln!("Warning: Failed to cache record of {}: {}", id, e);
+        }
+        if !results.iter().any(&accept) {
+            eprintln!("Warning: Record of {} does not match the document", id);
+            continue;
         }
+
+        for meta in &mut results {
+            // Some resolvers use the URL of the DOI as citation key
+            if meta.key().contains('/') {
+                let key = generate_key(meta.authors(), meta.year());
+                meta.set_generated_key(key);
+            }
+            meta.add_identifier(id.clone());
+        }
+        return Some(results);
     }
 
-    Ok(LibraryEntry::new(meta, tags, paths, digest))
+    None
 }
 
-fn calculate_digest<P: AsRef<Path>>(path: P) -> Result<FileDigest, ImportError> {
-    // This can be done more elegantly (by not loading the entire file) but should suffice
-    // for now
-    let mut file_reader = BufReader::new(File::open(path.as_ref())?);
-    let mut file_bytes: Vec<u8> = Vec::new();
-    copy(&mut file_reader, &mut file_bytes)?;
-    let mut hasher = Sha256::default();
-    hasher.input(file_bytes.as_slice());
+/// Formats a parse error as BibTeX comment to be shown in the editor.
+fn error_comment(descr: &str) -> String {
+    let error: String = descr.lines().map(|l| format!("% {}\n", l)).collect();
+    format!("% Failed to parse the bibliography:\n{}%\n", error)
+}
 
-    Ok(hasher.result())
+/// Generates a citation key of the form `lastname2019` for entries without a given key.
+fn generate_key(authors: &[String], year: u32) -> String {
+    let last_name = authors
+        .first()
+        .map(|a| {
+            if a.contains(',') {
+                a.split(',').next().unwrap_or("")
+            } else {
+                a.split(' ').next_back().unwrap_or("")
+            }
+        })
+        .unwrap_or("")
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+
+    format!("{}{}", last_name, year)
+}
+
+/// Lets the user enter a bibliography in a text editor which initially contains `content`.
+/// As long as the entered text can not be parsed, the editor is opened again with the error
+/// message prepended.
+fn edit_bibliography(content: String, conf: &Configuration) -> ImportResult {
+    let mut content = String::from(BIBLIOGRAPHY_HEADER) + &content;
+    loop {
+        let edited = editor::edit(&content, ".bib", conf)?;
+        let bibliography: String = edited
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('%'))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        if bibliography.trim().is_empty() {
+            return Err(ImportError::NoBibliographyFound(String::from(
+                "No bibliography entered",
+            )));
+        }
+
+        match bib::import_strict(bibliography.clone()) {
+            Ok(results) => return Ok(results),
+            Err(ImportError::Parse(descr)) => {
+                content = format!(
+                    "{}{}{}",
+                    BIBLIOGRAPHY_HEADER,
+                    error_comment(&descr),
+                    bibliography
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 mod bib {
@@ -213,7 +794,12 @@ mod bib {
     //separated_nonempty_list!(tag!(" and "), map!(non_empty, String::from)));
 
     fn parse_author_list(authors: &str) -> Vec<String> {
-        authors.split(" and ").map(String::from).collect()
+        authors
+            .split(" and ")
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(String::from)
+            .collect()
     }
 
     fn parse_entry_type(name: &str) -> Result<LibraryEntryType, ImportError> {
@@ -240,28 +826,31 @@ mod bib {
     fn import_bib(b: &Bibliography) -> Result<LibraryEntryMeta, ImportError> {
         let tags: TagMap = b.tags().iter().cloned().collect();
 
-        let find_tag = |tag: &str| {
-            b.tags()
-                .iter()
-                .find(|&(ref name, _)| name.to_lowercase() == tag)
-        };
+        let find_tag = |tag: &str| b.tags().iter().find(|(name, _)| name.to_lowercase() == tag);
         let find_tag_required = |tag: &str| {
             find_tag(tag).ok_or_else(|| ImportError::Parse(format!("Missing tag \"{}\"", tag)))
         };
 
         let entry_type = parse_entry_type(b.entry_type())?;
         let (_, title) = find_tag_required("title")?;
+        if title.trim().is_empty() {
+            return Err(ImportError::Parse(String::from("Tag \"title\" is empty")));
+        }
         let authors = parse_author_list(&find_tag_required("author")?.1);
+        if authors.is_empty() {
+            return Err(ImportError::Parse(String::from("Tag \"author\" is empty")));
+        }
         let year = match find_tag_required("year")?.1.parse::<u32>() {
             Ok(y) => y,
             Err(e) => return Err(ImportError::Parse(format!("Failed to parse year: {}", e))),
         };
         let month = match find_tag("month") {
-            Some(&(_, ref s)) => Some(s.parse::<Month>()?),
+            Some((_, s)) => Some(s.parse::<Month>()?),
             None => None,
         };
 
-        Ok(LibraryEntryMeta::new(
+        let identifiers = ident::from_tags(&tags);
+        let mut meta = LibraryEntryMeta::new(
             String::from(b.citation_key()),
             entry_type,
             title.clone(),
@@ -269,7 +858,30 @@ mod bib {
             year,
             month,
             Some(tags),
-        ))
+        );
+        for id in identifiers {
+            meta.add_identifier(id);
+        }
+
+        Ok(meta)
+    }
+
+    /// Imports all entries of a BibTeX file, failing on the first entry which could not be
+    /// imported.
+    pub fn import_strict(file: String) -> ImportResult {
+        let bibs = Bibtex::parse(&file)?;
+
+        bibs.bibliographies()
+            .iter()
+            .map(|bib| {
+                import_bib(bib).map_err(|e| match e {
+                    ImportError::Parse(descr) => {
+                        ImportError::Parse(format!("Entry {}: {}", bib.citation_key(), descr))
+                    }
+                    e => e,
+                })
+            })
+            .collect()
     }
 
     pub fn import(file: String) -> ImportResult {
@@ -294,3 +906,1160 @@ mod bib {
             .collect())
     }
 }
+
+mod ris {
+    use super::*;
+
+    /// Tags which may contain the authors of a record, in order of precedence.
+    const AUTHOR_TAGS: [&str; 2] = ["AU", "A1"];
+    /// Tags which may contain the title of a record, in order of precedence.
+    const TITLE_TAGS: [&str; 3] = ["TI", "T1", "CT"];
+    /// Tags which may contain the publication date of a record, in order of precedence.
+    const DATE_TAGS: [&str; 3] = ["PY", "Y1", "DA"];
+
+    /// A single RIS record with the values of all tags in the order of their appearance.
+    type Record = Vec<(String, String)>;
+
+    /// Splits a RIS line of the form `TY  - JOUR` into its tag and value.
+    fn parse_line(line: &str) -> Option<(&str, &str)> {
+        let tag = line.get(0..2)?;
+        let rest = line.get(2..)?.trim_start_matches(' ');
+        if !tag.chars().all(|c| c.is_ascii_alphanumeric()) || !rest.starts_with('-') {
+            return None;
+        }
+
+        Some((tag, rest[1..].trim()))
+    }
+
+    fn parse_records(file: &str) -> Result<Vec<Record>, ImportError> {
+        let mut records: Vec<Record> = Vec::new();
+        let mut current: Option<Record> = None;
+        for (n, line) in file.lines().enumerate() {
+            let line = line.trim_start_matches('\u{feff}').trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            match (parse_line(line), current.as_mut()) {
+                (Some(("TY", value)), None) => {
+                    current = Some(vec![(String::from("TY"), String::from(value))])
+                }
+                (Some(("ER", _)), Some(_)) => records.extend(current.take()),
+                (Some((tag, value)), Some(record)) => {
+                    record.push((String::from(tag), String::from(value)))
+                }
+                // Continuation of a multi-line value
+                (None, Some(record)) => {
+                    if let Some((_, value)) = record.last_mut() {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                    }
+                }
+                (_, None) => {
+                    return Err(ImportError::Parse(format!(
+                        "Line {}: Expected record start \"TY  -\"",
+                        n + 1
+                    )))
+                }
+            }
+        }
+        if current.is_some() {
+            return Err(ImportError::Parse(String::from(
+                "Record not terminated by \"ER  -\"",
+            )));
+        }
+
+        Ok(records)
+    }
+
+    fn parse_entry_type(name: &str) -> LibraryEntryType {
+        match name.to_uppercase().as_str() {
+            "JOUR" | "JFULL" | "EJOUR" | "MGZN" | "NEWS" => LibraryEntryType::Article,
+            "BOOK" | "EBOOK" | "EDBOOK" => LibraryEntryType::Book,
+            "PAMP" => LibraryEntryType::Booklet,
+            "CHAP" | "ECHAP" => LibraryEntryType::InBook,
+            "CONF" | "CPAPER" => LibraryEntryType::InProceedings,
+            "THES" => LibraryEntryType::Thesis,
+            "RPRT" => LibraryEntryType::Techreport,
+            "UNPB" | "MANSCPT" => LibraryEntryType::Unpublished,
+            _ => LibraryEntryType::Misc,
+        }
+    }
+
+    /// Parses dates given as `YYYY`, `YYYY/MM/DD/other` or `YYYY-MM-DD`. Months which can not
+    /// be parsed (like seasons) are ignored.
+    fn parse_date(date: &str) -> Result<(u32, Option<Month>), ImportError> {
+        let mut parts = date.split(['/', '-']).map(str::trim);
+        let year = match parts.next().map(str::parse::<u32>) {
+            Some(Ok(y)) => y,
+            _ => return Err(ImportError::Parse(format!("Failed to parse date {}", date))),
+        };
+        let month = parts.next().and_then(|m| m.parse::<Month>().ok());
+
+        Ok((year, month))
+    }
+
+    fn import_record(record: &Record) -> Result<LibraryEntryMeta, ImportError> {
+        let find_all = |tags: &[&str]| -> Vec<String> {
+            tags.iter()
+                .map(|&tag| {
+                    record
+                        .iter()
+                        .filter(|(t, v)| t == tag && !v.is_empty())
+                        .map(|(_, v)| v.clone())
+                        .collect::<Vec<String>>()
+                })
+                .find(|values| !values.is_empty())
+                .unwrap_or_default()
+        };
+        let find_required = |tags: &[&str]| {
+            find_all(tags)
+                .into_iter()
+                .next()
+                .ok_or_else(|| ImportError::Parse(format!("Missing tag \"{}\"", tags[0])))
+        };
+
+        // Repeated tags like AU are joined line by line
+        let mut tags = TagMap::new();
+        for (tag, value) in record {
+            tags.entry(tag.clone())
+                .and_modify(|v: &mut String| {
+                    v.push('\n');
+                    v.push_str(value);
+                })
+                .or_insert_with(|| value.clone());
+        }
+
+        let entry_type = parse_entry_type(&find_required(&["TY"])?);
+        let title = find_required(&TITLE_TAGS)?;
+        let authors = find_all(&AUTHOR_TAGS);
+        if authors.is_empty() {
+            return Err(ImportError::Parse(String::from("Missing tag \"AU\"")));
+        }
+        let (year, mut month) = parse_date(&find_required(&DATE_TAGS)?)?;
+        if month.is_none() {
+            if let Some(date) = find_all(&["DA"]).first() {
+                month = parse_date(date)?.1;
+            }
+        }
+        let key = find_all(&["ID"]).into_iter().next();
+
+        let identifiers = ident::from_tags(&tags);
+        let mut meta = LibraryEntryMeta::new(
+            key.clone().unwrap_or_default(),
+            entry_type,
+            title,
+            authors,
+            year,
+            month,
+            Some(tags),
+        );
+        if key.is_none() {
+            let generated = generate_key(meta.authors(), meta.year());
+            meta.set_generated_key(generated);
+        }
+        for id in identifiers {
+            meta.add_identifier(id);
+        }
+
+        Ok(meta)
+    }
+
+    pub fn import(file: String) -> ImportResult {
+        Ok(parse_records(&file)?
+            .iter()
+            .enumerate()
+            .filter_map(|(i, record)| match import_record(record) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    eprintln!("Warning: Failed to load record {}: {}", i + 1, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const RECORDS: &str = "\u{feff}TY  - JOUR
+AU  - Doe, John
+AU  - Roe, Jane
+TI  - A long title
+  continued on the next line
+PY  - 2020/03/15/
+DO  - 10.1000/xyz123
+ER  -
+
+TY  - CHAP
+ID  - roe19
+A1  - Roe, Jane
+T1  - A chapter
+Y1  - 2019
+DA  - 2019/Winter
+ER  -
+";
+
+        #[test]
+        fn parse_line_splits_tag_and_value() {
+            assert_eq!(parse_line("TY  - JOUR"), Some(("TY", "JOUR")));
+            assert_eq!(parse_line("ER  -"), Some(("ER", "")));
+            assert_eq!(parse_line("AU - Doe, John"), Some(("AU", "Doe, John")));
+            assert_eq!(parse_line("continued text"), None);
+            assert_eq!(parse_line("A"), None);
+        }
+
+        #[test]
+        fn parse_date_formats() {
+            assert_eq!(parse_date("2020").unwrap(), (2020, None));
+            assert_eq!(parse_date("2020/03/15/").unwrap(), (2020, Some(Month::Mar)));
+            assert_eq!(parse_date("2020-11-02").unwrap(), (2020, Some(Month::Nov)));
+            assert_eq!(parse_date("2020/Spring").unwrap(), (2020, None));
+            assert!(parse_date("Spring 2020").is_err());
+        }
+
+        #[test]
+        fn import_records() {
+            let results = import(String::from(RECORDS)).unwrap();
+            assert_eq!(results.len(), 2);
+
+            let article = &results[0];
+            assert!(matches!(article.entry_type(), LibraryEntryType::Article));
+            assert_eq!(article.title(), "A long title continued on the next line");
+            assert_eq!(article.authors(), &vec!["Doe, John", "Roe, Jane"]);
+            assert_eq!((article.year(), article.month()), (2020, Some(Month::Mar)));
+            assert_eq!(article.key(), "doe2020");
+            assert!(article.has_generated_key());
+            assert_eq!(
+                article.identifiers(),
+                &[Identifier::Doi(String::from("10.1000/xyz123"))]
+            );
+
+            let chapter = &results[1];
+            assert!(matches!(chapter.entry_type(), LibraryEntryType::InBook));
+            assert_eq!(chapter.title(), "A chapter");
+            assert_eq!(chapter.key(), "roe19");
+            assert!(!chapter.has_generated_key());
+            assert_eq!((chapter.year(), chapter.month()), (2019, None));
+        }
+
+        #[test]
+        fn records_missing_required_tags_are_skipped() {
+            let record = "TY  - JOUR\nTI  - No authors\nPY  - 2020\nER  -\n";
+            assert!(import(String::from(record)).unwrap().is_empty());
+        }
+
+        #[test]
+        fn malformed_files_are_rejected() {
+            assert!(import(String::from("AU  - Doe, John\n")).is_err());
+            assert!(import(String::from("TY  - JOUR\nAU  - Doe, John\n")).is_err());
+        }
+    }
+}
+
+mod csl {
+    use super::*;
+    use serde_json::{Map, Value};
+
+    fn parse_entry_type(name: &str) -> LibraryEntryType {
+        match name {
+            "article" | "article-journal" | "article-magazine" | "article-newspaper" => {
+                LibraryEntryType::Article
+            }
+            "book" => LibraryEntryType::Book,
+            "pamphlet" => LibraryEntryType::Booklet,
+            "chapter" => LibraryEntryType::InBook,
+            "paper-conference" => LibraryEntryType::InProceedings,
+            "thesis" => LibraryEntryType::Thesis,
+            "report" => LibraryEntryType::Techreport,
+            "manuscript" => LibraryEntryType::Unpublished,
+            _ => LibraryEntryType::Misc,
+        }
+    }
+
+    /// Formats a CSL name variable as `Family, Given` like it is done in BibTeX.
+    fn parse_author(author: &Value) -> Option<String> {
+        let part = |name: &str| author.get(name).and_then(Value::as_str);
+        match (part("family"), part("given"), part("literal")) {
+            (Some(family), Some(given), _) => {
+                let family = match part("non-dropping-particle") {
+                    Some(particle) => format!("{} {}", particle, family),
+                    None => String::from(family),
+                };
+                Some(format!("{}, {}", family, given))
+            }
+            (Some(family), None, _) => Some(String::from(family)),
+            (None, _, Some(literal)) => Some(String::from(literal)),
+            _ => None,
+        }
+    }
+
+    /// Extracts year and month from a CSL date variable, preferring `date-parts` over the
+    /// `raw` representation.
+    fn parse_date(date: &Value) -> Result<(u32, Option<Month>), ImportError> {
+        // Date parts are sometimes given as strings instead of numbers
+        let as_number = |v: &Value| {
+            v.as_u64()
+                .map(|n| n as u32)
+                .or_else(|| v.as_str().and_then(|s| s.trim().parse::<u32>().ok()))
+        };
+
+        if let Some(parts) = date
+            .get("date-parts")
+            .and_then(|p| p.get(0))
+            .and_then(Value::as_array)
+        {
+            let year = parts.first().and_then(as_number).ok_or_else(|| {
+                ImportError::Parse(String::from("Failed to parse year of \"issued\""))
+            })?;
+            let month = match parts.get(1).and_then(as_number) {
+                Some(m) => Some(Month::from_number(m)?),
+                None => None,
+            };
+            return Ok((year, month));
+        }
+
+        date.get("raw")
+            .or_else(|| date.get("literal"))
+            .and_then(Value::as_str)
+            .and_then(|raw| raw.get(0..4))
+            .and_then(|y| y.parse::<u32>().ok())
+            .map(|y| (y, None))
+            .ok_or_else(|| ImportError::Parse(String::from("Failed to parse \"issued\"")))
+    }
+
+    fn import_item(item: &Map<String, Value>) -> Result<LibraryEntryMeta, ImportError> {
+        let tags: TagMap = item
+            .iter()
+            .map(|(name, value)| match value {
+                Value::String(s) => (name.clone(), s.clone()),
+                v => (name.clone(), v.to_string()),
+            })
+            .collect();
+
+        let find_required = |name: &str| {
+            item.get(name)
+                .ok_or_else(|| ImportError::Parse(format!("Missing field \"{}\"", name)))
+        };
+        let key = match find_required("id")? {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
+        let entry_type = parse_entry_type(find_required("type")?.as_str().unwrap_or(""));
+        let title = find_required("title")?
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| ImportError::Parse(String::from("Field \"title\" is no string")))?;
+        let authors: Vec<String> = find_required("author")?
+            .as_array()
+            .map(|a| a.iter().filter_map(parse_author).collect())
+            .unwrap_or_default();
+        if authors.is_empty() {
+            return Err(ImportError::Parse(String::from(
+                "No valid \"author\" found",
+            )));
+        }
+        let (year, month) = parse_date(find_required("issued")?)?;
+
+        let identifiers = ident::from_tags(&tags);
+        let mut meta =
+            LibraryEntryMeta::new(key, entry_type, title, authors, year, month, Some(tags));
+        for id in identifiers {
+            meta.add_identifier(id);
+        }
+
+        Ok(meta)
+    }
+
+    pub fn import(file: String) -> ImportResult {
+        let items = match serde_json::from_str::<Value>(&file) {
+            Ok(Value::Array(items)) => items,
+            Ok(item @ Value::Object(_)) => vec![item],
+            Ok(_) => {
+                return Err(ImportError::Parse(String::from(
+                    "Expected an array of CSL items",
+                )))
+            }
+            Err(e) => return Err(ImportError::Parse(e.to_string())),
+        };
+
+        Ok(items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| {
+                let result = match item.as_object() {
+                    Some(item) => import_item(item),
+                    None => Err(ImportError::Parse(String::from("Item is no object"))),
+                };
+                match result {
+                    Ok(r) => Some(r),
+                    Err(e) => {
+                        eprintln!("Warning: Failed to load item {}: {}", i + 1, e);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ITEMS: &str = r#"[
+            {
+                "id": "doe2020",
+                "type": "article-journal",
+                "title": "A title",
+                "author": [
+                    {"family": "Doe", "given": "John"},
+                    {"family": "Beethoven", "given": "Ludwig", "non-dropping-particle": "van"},
+                    {"literal": "The Consortium"}
+                ],
+                "issued": {"date-parts": [[2020, 3]]},
+                "DOI": "10.1000/xyz123"
+            },
+            {
+                "id": 17,
+                "type": "chapter",
+                "title": "A chapter",
+                "author": [{"family": "Roe"}],
+                "issued": {"raw": "2019-05"}
+            },
+            {"id": "broken", "type": "book", "title": "No authors", "issued": {"raw": "2018"}}
+        ]"#;
+
+        #[test]
+        fn parse_authors() {
+            let author = |json: &str| parse_author(&serde_json::from_str(json).unwrap());
+            assert_eq!(
+                author(r#"{"family": "Doe", "given": "John"}"#).unwrap(),
+                "Doe, John"
+            );
+            assert_eq!(author(r#"{"family": "Doe"}"#).unwrap(), "Doe");
+            assert_eq!(author(r#"{"literal": "NASA"}"#).unwrap(), "NASA");
+            assert_eq!(author(r#"{"given": "John"}"#), None);
+        }
+
+        #[test]
+        fn parse_dates() {
+            let date = |json: &str| parse_date(&serde_json::from_str(json).unwrap());
+            assert_eq!(
+                date(r#"{"date-parts": [[2020, 3, 1]]}"#).unwrap(),
+                (2020, Some(Month::Mar))
+            );
+            assert_eq!(
+                date(r#"{"date-parts": [["2020", "11"]]}"#).unwrap(),
+                (2020, Some(Month::Nov))
+            );
+            assert_eq!(date(r#"{"date-parts": [[2020]]}"#).unwrap(), (2020, None));
+            assert_eq!(date(r#"{"literal": "2019 spring"}"#).unwrap(), (2019, None));
+            assert!(date(r#"{"date-parts": [[2020, 13]]}"#).is_err());
+            assert!(date(r#"{"raw": "spring"}"#).is_err());
+        }
+
+        #[test]
+        fn import_items() {
+            let results = import(String::from(ITEMS)).unwrap();
+            assert_eq!(results.len(), 2);
+
+            let article = &results[0];
+            assert_eq!(article.key(), "doe2020");
+            assert!(matches!(article.entry_type(), LibraryEntryType::Article));
+            assert_eq!(article.title(), "A title");
+            assert_eq!(
+                article.authors(),
+                &vec!["Doe, John", "van Beethoven, Ludwig", "The Consortium"]
+            );
+            assert_eq!((article.year(), article.month()), (2020, Some(Month::Mar)));
+            assert_eq!(
+                article.identifiers(),
+                &[Identifier::Doi(String::from("10.1000/xyz123"))]
+            );
+
+            let chapter = &results[1];
+            assert_eq!(chapter.key(), "17");
+            assert!(matches!(chapter.entry_type(), LibraryEntryType::InBook));
+            assert_eq!((chapter.year(), chapter.month()), (2019, None));
+        }
+
+        #[test]
+        fn single_items_are_accepted() {
+            let item = r#"{"id": "x", "type": "book", "title": "T",
+                "author": [{"family": "Doe"}], "issued": {"raw": "2001"}}"#;
+            assert_eq!(import(String::from(item)).unwrap().len(), 1);
+            assert!(import(String::from("42")).is_err());
+            assert!(import(String::from("[{")).is_err());
+        }
+    }
+}
+
+mod pdf {
+    use super::*;
+    use lopdf::{decode_text_string, Dictionary, Document, Object};
+    use regex::Regex;
+
+    impl From<lopdf::Error> for ImportError {
+        fn from(err: lopdf::Error) -> ImportError {
+            ImportError::Parse(format!("Failed to read PDF: {}", err))
+        }
+    }
+
+    lazy_static! {
+        static ref XMP_TITLE: Regex =
+            Regex::new(r"(?s)<dc:title\b.*?<rdf:li\b[^>]*>(.*?)</rdf:li>").unwrap();
+        static ref XMP_CREATOR: Regex =
+            Regex::new(r"(?s)<dc:creator\b[^>]*>(.*?)</dc:creator>").unwrap();
+        static ref XMP_LIST_ITEM: Regex = Regex::new(r"(?s)<rdf:li\b[^>]*>(.*?)</rdf:li>").unwrap();
+        static ref XMP_DOI: Regex =
+            Regex::new(r#"<prism:doi>\s*([^<]+?)\s*<|prism:doi="([^"]+)""#).unwrap();
+        static ref XMP_CREATE_DATE: Regex =
+            Regex::new(r#"xmp:CreateDate(?:>|=")\s*(\d{4})(?:-(\d{2}))?"#).unwrap();
+        static ref INFO_DATE: Regex = Regex::new(r"^(?:D:)?(\d{4})(\d{2})?").unwrap();
+    }
+
+    /// Metadata embedded in a document of which any field may be missing.
+    #[derive(Debug, Default)]
+    pub struct DocumentMetadata {
+        title: Option<String>,
+        authors: Vec<String>,
+        year: Option<u32>,
+        month: Option<Month>,
+        doi: Option<String>,
+        tags: TagMap,
+    }
+
+    impl DocumentMetadata {
+        /// Returns the identifiers contained in the metadata.
+        pub fn identifiers(&self) -> Vec<Identifier> {
+            self.doi.iter().map(|doi| Identifier::doi(doi)).collect()
+        }
+
+        /// Converts the metadata into a library entry, failing if a required field is missing.
+        pub fn to_meta(&self) -> Result<LibraryEntryMeta, ImportError> {
+            let missing = |field: &str| ImportError::Parse(format!("Missing field \"{}\"", field));
+            let title = self.title.clone().ok_or_else(|| missing("title"))?;
+            if self.authors.is_empty() {
+                return Err(missing("author"));
+            }
+            let year = self.year.ok_or_else(|| missing("year"))?;
+
+            let mut meta = LibraryEntryMeta::new(
+                String::new(),
+                LibraryEntryType::Misc,
+                title,
+                self.authors.clone(),
+                year,
+                self.month,
+                Some(self.tags.clone()),
+            );
+            meta.set_generated_key(generate_key(&self.authors, year));
+            if let Some(ref doi) = self.doi {
+                meta.add_identifier(Identifier::doi(doi));
+            }
+
+            Ok(meta)
+        }
+
+        /// Returns the key generated for the metadata, which is also used by `to_bibtex`.
+        pub fn generated_key(&self) -> String {
+            generate_key(&self.authors, self.year.unwrap_or(0))
+        }
+
+        /// Formats the metadata as BibTeX entry to be completed by the user, leaving missing
+        /// fields empty.
+        pub fn to_bibtex(&self) -> String {
+            let year = self.year.map(|y| y.to_string()).unwrap_or_default();
+            let mut bibtex = format!(
+                "@misc{{{},\n    author = {{{}}},\n    title = {{{}}},\n    year = {{{}}},\n",
+                self.generated_key(),
+                self.authors.join(" and "),
+                self.title.as_ref().map_or("", String::as_str),
+                year
+            );
+            if let Some(m) = self.month {
+                bibtex.push_str(&format!("    month = {{{}}},\n", m));
+            }
+            if let Some(ref doi) = self.doi {
+                bibtex.push_str(&format!("    doi = {{{}}},\n", doi));
+            }
+            bibtex.push_str("}\n");
+
+            bibtex
+        }
+    }
+
+    fn unescape_xml(s: &str) -> String {
+        s.trim()
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&")
+    }
+
+    /// Filters titles generated by office software like `Microsoft Word - paper.docx`.
+    fn is_useful_title(title: &str) -> bool {
+        let lower = title.trim().to_lowercase();
+        !(lower.is_empty()
+            || lower == "untitled"
+            || lower.starts_with("microsoft word - ")
+            || [".pdf", ".doc", ".docx", ".dvi", ".tex", ".ps"]
+                .iter()
+                .any(|ext| lower.ends_with(ext)))
+    }
+
+    /// Splits an author list like `A; B` or `A and B` into single authors.
+    fn split_authors(authors: &str) -> Vec<String> {
+        let separator = if authors.contains(';') { ";" } else { " and " };
+        authors
+            .split(separator)
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    fn parse_date(captures: Option<regex::Captures>) -> (Option<u32>, Option<Month>) {
+        match captures {
+            Some(c) => (
+                c.get(1).and_then(|y| y.as_str().parse().ok()),
+                c.get(2)
+                    .and_then(|m| m.as_str().parse::<u32>().ok())
+                    .and_then(|m| Month::from_number(m).ok()),
+            ),
+            None => (None, None),
+        }
+    }
+
+    fn info_dictionary(doc: &Document) -> Option<&Dictionary> {
+        doc.trailer
+            .get_deref(b"Info", doc)
+            .and_then(Object::as_dict)
+            .ok()
+    }
+
+    fn xmp_packet(doc: &Document) -> Option<String> {
+        let stream = doc
+            .catalog()
+            .and_then(|c| c.get_deref(b"Metadata", doc))
+            .and_then(Object::as_stream)
+            .ok()?;
+        let content = stream
+            .decompressed_content()
+            .unwrap_or_else(|_| stream.content.clone());
+
+        String::from_utf8(content).ok()
+    }
+
+    /// Loads a PDF file.
+    pub fn load(path: &Path) -> Result<Document, ImportError> {
+        Ok(Document::load(path)?)
+    }
+
+    /// Reads the document information dictionary and XMP metadata of a PDF document,
+    /// preferring the values of the XMP metadata.
+    pub fn read_metadata(doc: &Document) -> DocumentMetadata {
+        let mut metadata = DocumentMetadata::default();
+
+        if let Some(info) = info_dictionary(doc) {
+            for (name, value) in info.iter() {
+                if let Ok(text) = decode_text_string(value) {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    metadata.tags.insert(name, text);
+                }
+            }
+            metadata.title = metadata
+                .tags
+                .get("Title")
+                .filter(|t| is_useful_title(t))
+                .cloned();
+            metadata.authors = metadata
+                .tags
+                .get("Author")
+                .map(|a| split_authors(a))
+                .unwrap_or_default();
+            let (year, month) = parse_date(
+                metadata
+                    .tags
+                    .get("CreationDate")
+                    .and_then(|d| INFO_DATE.captures(d)),
+            );
+            metadata.year = year;
+            metadata.month = month;
+            metadata.doi = metadata
+                .tags
+                .iter()
+                .find(|(name, _)| name.to_lowercase() == "doi")
+                .map(|(_, doi)| doi.clone());
+        }
+
+        if let Some(xmp) = xmp_packet(doc) {
+            if let Some(title) = XMP_TITLE
+                .captures(&xmp)
+                .map(|c| unescape_xml(&c[1]))
+                .filter(|t| is_useful_title(t))
+            {
+                metadata.title = Some(title);
+            }
+            let creators: Vec<String> = XMP_CREATOR
+                .captures(&xmp)
+                .map(|c| {
+                    XMP_LIST_ITEM
+                        .captures_iter(&c[1])
+                        .map(|i| unescape_xml(&i[1]))
+                        .filter(|a| !a.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+            if !creators.is_empty() {
+                metadata.authors = creators;
+            }
+            if let Some(doi) = XMP_DOI
+                .captures(&xmp)
+                .and_then(|c| c.get(1).or_else(|| c.get(2)))
+            {
+                metadata.doi = Some(unescape_xml(doi.as_str()));
+            }
+            if let (Some(year), month) = parse_date(XMP_CREATE_DATE.captures(&xmp)) {
+                metadata.year = Some(year);
+                metadata.month = month;
+            }
+        }
+
+        if let Some(ref doi) = metadata.doi {
+            metadata.tags.insert(String::from("doi"), doi.clone());
+        }
+
+        metadata
+    }
+}
+
+mod ident {
+    use super::*;
+    use lopdf::Document;
+    use pdf_extract::{output_doc_page, PlainTextOutput};
+    use regex::Regex;
+    use std::panic;
+    use std::panic::AssertUnwindSafe;
+
+    /// The number of pages scanned for identifiers, which are usually printed on the first page.
+    const SCANNED_PAGES: u32 = 2;
+
+    /// The identifiers found by scanning a document.
+    #[derive(Debug, Default)]
+    pub struct Scan {
+        /// Identifiers which are strong evidence of the identity of the document, namely those
+        /// in its file name and those labelled as such like `doi:10.1000/xyz`
+        pub own: Vec<Identifier>,
+        /// All other identifiers printed in the document, which may belong to cited works
+        pub candidates: Vec<Identifier>,
+        /// The text of the first pages of the document
+        pub text: String,
+    }
+
+    lazy_static! {
+        static ref DOI: Regex = Regex::new(r#"\b(10\.\d{4,9}/[^\s"<>{}]+)"#).unwrap();
+        static ref DOI_LABELLED: Regex =
+            Regex::new(r#"(?i)(?:\bdoi:\s*|doi\.org/)(10\.\d{4,9}/[^\s"<>{}]+)"#).unwrap();
+        static ref ARXIV_PREFIXED: Regex =
+            Regex::new(r"(?i)\barxiv:\s*(\d{4}\.\d{4,5}|[a-z-]+(?:\.[a-z]{2})?/\d{7})(?:v\d+)?")
+                .unwrap();
+        static ref ARXIV_BARE: Regex =
+            Regex::new(r"^(\d{4}\.\d{4,5}|[a-z-]+(?:\.[a-z]{2})?/\d{7})(?:v\d+)?$").unwrap();
+        static ref ARXIV_URL: Regex =
+            Regex::new(r"arxiv\.org/(?:abs|pdf)/(\d{4}\.\d{4,5}|[a-z-]+(?:\.[a-z]{2})?/\d{7})")
+                .unwrap();
+        static ref ISBN: Regex =
+            Regex::new(r"(?i)\bISBN(?:-1[03])?:?\s*([0-9][0-9 -]{8,15}[0-9X])\b").unwrap();
+    }
+
+    /// Strips punctuation which commonly follows a DOI in running text.
+    fn trim_doi(doi: &str) -> &str {
+        let mut doi = doi.trim_end_matches(['.', ',', ';', ':', '\'']);
+        // Only strip closing parentheses which are not part of the DOI itself
+        while doi.ends_with(')') && doi.matches('(').count() < doi.matches(')').count() {
+            doi = &doi[..doi.len() - 1];
+        }
+        doi
+    }
+
+    /// Normalizes an ISBN to its digits and validates its check digit.
+    fn parse_isbn(isbn: &str) -> Option<Identifier> {
+        let digits: Vec<char> = isbn
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let value = |c: char| c.to_digit(10).unwrap_or(10);
+        let valid = match digits.len() {
+            10 => {
+                digits[..9].iter().all(char::is_ascii_digit)
+                    && digits
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &c)| (10 - i as u32) * value(c))
+                        .sum::<u32>()
+                        % 11
+                        == 0
+            }
+            13 => {
+                digits.iter().all(char::is_ascii_digit)
+                    && digits
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &c)| if i % 2 == 0 { 1 } else { 3 } * value(c))
+                        .sum::<u32>()
+                        % 10
+                        == 0
+            }
+            _ => false,
+        };
+
+        if valid {
+            Some(Identifier::Isbn(digits.into_iter().collect()))
+        } else {
+            None
+        }
+    }
+
+    /// Finds all identifiers printed in a text, in the order DOI, arXiv id, ISBN.
+    pub fn from_text(text: &str) -> Vec<Identifier> {
+        let mut identifiers: Vec<Identifier> = Vec::new();
+        let mut push = |id: Identifier| {
+            if !identifiers.contains(&id) {
+                identifiers.push(id);
+            }
+        };
+        for c in DOI.captures_iter(text) {
+            push(Identifier::doi(trim_doi(&c[1])));
+        }
+        for c in ARXIV_PREFIXED
+            .captures_iter(text)
+            .chain(ARXIV_URL.captures_iter(text))
+        {
+            push(Identifier::Arxiv(String::from(&c[1])));
+        }
+        for isbn in ISBN.captures_iter(text).filter_map(|c| parse_isbn(&c[1])) {
+            push(isbn);
+        }
+
+        identifiers
+    }
+
+    /// Finds identifiers in the tags of an imported bibliography entry.
+    pub fn from_tags(tags: &TagMap) -> Vec<Identifier> {
+        let mut identifiers: Vec<Identifier> = Vec::new();
+        for (name, value) in tags {
+            let found = match name.to_lowercase().as_str() {
+                "doi" | "do" | "url" | "ur" | "note" => from_text(value),
+                "eprint" | "arxiv" | "arxivid" => ARXIV_BARE
+                    .captures(value.trim())
+                    .map(|c| vec![Identifier::Arxiv(String::from(&c[1]))])
+                    .unwrap_or_else(|| from_text(value)),
+                "isbn" | "sn" => value
+                    .split([',', ';', '\n'])
+                    .filter_map(parse_isbn)
+                    .collect(),
+                _ => Vec::new(),
+            };
+            for id in found {
+                if !identifiers.contains(&id) {
+                    identifiers.push(id);
+                }
+            }
+        }
+
+        identifiers
+    }
+
+    /// Finds identifiers in a file name like `2101.01234v2.pdf` or `10.1000_xyz123.pdf`.
+    pub fn from_file_name(path: &Path) -> Vec<Identifier> {
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        if let Some(c) = ARXIV_BARE.captures(stem) {
+            return vec![Identifier::Arxiv(String::from(&c[1]))];
+        }
+
+        // Slashes can not be part of file names and are commonly replaced by underscores
+        let mut identifiers = from_text(stem);
+        if identifiers.is_empty() && stem.starts_with("10.") {
+            identifiers = from_text(&stem.replacen('_', "/", 1));
+        }
+        identifiers
+    }
+
+    /// Extracts the text of the first pages of a PDF document.
+    fn first_pages_text(doc: &Document) -> Option<String> {
+        let pages = doc.get_pages().len() as u32;
+
+        // Text extraction panics on some malformed documents, which should not abort the
+        // import. The default hook would print the caught panic, so it is silenced meanwhile.
+        // Replacing the process-wide hook is safe since documents are only scanned on the main
+        // thread while no other threads are running, and the hook is restored right after.
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let text = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut text = String::new();
+            for page in 1..=pages.min(SCANNED_PAGES) {
+                let mut output = PlainTextOutput::new(&mut text);
+                if output_doc_page(doc, &mut output, page).is_err() {
+                    break;
+                }
+            }
+            text
+        }));
+        panic::set_hook(hook);
+
+        text.ok()
+    }
+
+    /// Finds the identifiers explicitly labelled as such, like `doi:10.1000/xyz`,
+    /// `https://doi.org/10.1000/xyz`, `arXiv:2101.01234` or `ISBN 0-201-03801-3`.
+    fn labelled(text: &str) -> Vec<Identifier> {
+        DOI_LABELLED
+            .captures_iter(text)
+            .map(|c| Identifier::doi(trim_doi(&c[1])))
+            .chain(
+                ARXIV_PREFIXED
+                    .captures_iter(text)
+                    .chain(ARXIV_URL.captures_iter(text))
+                    .map(|c| Identifier::Arxiv(String::from(&c[1]))),
+            )
+            .chain(ISBN.captures_iter(text).filter_map(|c| parse_isbn(&c[1])))
+            .collect()
+    }
+
+    /// Scans the file name and, for PDF documents, the text of the first pages for identifiers.
+    /// The PDF document is loaded unless it is given.
+    pub fn scan_document(path: &Path, doc: Option<&Document>) -> Scan {
+        let mut scan = Scan {
+            own: from_file_name(path),
+            ..Scan::default()
+        };
+        let loaded = match doc {
+            None if is_pdf(path) => Document::load(path).ok(),
+            _ => None,
+        };
+        let text = match doc.or(loaded.as_ref()).and_then(first_pages_text) {
+            Some(t) => t,
+            None => return scan,
+        };
+
+        for id in labelled(&text) {
+            if !scan.own.contains(&id) {
+                scan.own.push(id);
+            }
+        }
+        for id in from_text(&text) {
+            if !scan.own.contains(&id) && !scan.candidates.contains(&id) {
+                scan.candidates.push(id);
+            }
+        }
+        scan.text = text;
+
+        scan
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn doi(doi: &str) -> Identifier {
+            Identifier::Doi(String::from(doi))
+        }
+
+        fn arxiv(id: &str) -> Identifier {
+            Identifier::Arxiv(String::from(id))
+        }
+
+        fn isbn(isbn: &str) -> Identifier {
+            Identifier::Isbn(String::from(isbn))
+        }
+
+        #[test]
+        fn parse_isbn_validates_check_digits() {
+            assert_eq!(parse_isbn("0-201-03801-3"), Some(isbn("0201038013")));
+            assert_eq!(parse_isbn("0 8044 2957 x"), Some(isbn("080442957X")));
+            assert_eq!(parse_isbn("978-0-201-89683-1"), Some(isbn("9780201896831")));
+            assert_eq!(parse_isbn("0-201-03801-4"), None);
+            assert_eq!(parse_isbn("978-0-201-89683-2"), None);
+            assert_eq!(parse_isbn("X-201-03801-3"), None);
+            assert_eq!(parse_isbn("12345"), None);
+        }
+
+        #[test]
+        fn dois_in_text() {
+            assert_eq!(
+                from_text("See 10.1000/XYZ.123, and (10.1000/a(b)c)."),
+                [doi("10.1000/xyz.123"), doi("10.1000/a(b)c")]
+            );
+            assert_eq!(
+                from_text("https://doi.org/10.1145/359545.359563;"),
+                [doi("10.1145/359545.359563")]
+            );
+            assert_eq!(from_text("version 10.1/2 or 110.1000/x"), []);
+        }
+
+        #[test]
+        fn arxiv_ids_in_text() {
+            assert_eq!(
+                from_text("arXiv:2101.01234v2 [cs.LG] and arxiv.org/abs/hep-th/9901001"),
+                [arxiv("2101.01234"), arxiv("hep-th/9901001")]
+            );
+            assert_eq!(from_text("released 2101.01234"), []);
+        }
+
+        #[test]
+        fn isbns_in_text() {
+            assert_eq!(
+                from_text("ISBN-13: 978-0-201-89683-1, ISBN 0-201-03801-4"),
+                [isbn("9780201896831")]
+            );
+        }
+
+        #[test]
+        fn only_labelled_identifiers_are_labelled() {
+            let text = "doi:10.1000/own https://doi.org/10.1000/url cites 10.1000/cited \
+                        arXiv:2101.01234 ISBN 0-201-03801-3";
+            assert_eq!(
+                labelled(text),
+                [
+                    doi("10.1000/own"),
+                    doi("10.1000/url"),
+                    arxiv("2101.01234"),
+                    isbn("0201038013")
+                ]
+            );
+            assert!(from_text(text).contains(&doi("10.1000/cited")));
+        }
+
+        #[test]
+        fn identifiers_in_file_names() {
+            assert_eq!(
+                from_file_name(Path::new("/tmp/2101.01234v2.pdf")),
+                [arxiv("2101.01234")]
+            );
+            assert_eq!(
+                from_file_name(Path::new("10.1000_xyz123.pdf")),
+                [doi("10.1000/xyz123")]
+            );
+            assert_eq!(from_file_name(Path::new("paper.pdf")), []);
+        }
+
+        #[test]
+        fn identifiers_in_tags() {
+            let tags: TagMap = [
+                ("doi", "https://doi.org/10.1000/ABC"),
+                ("eprint", "2101.01234"),
+                ("isbn", "0-201-03801-3; invalid"),
+                ("abstract", "cites 10.1000/other"),
+            ]
+            .iter()
+            .map(|&(name, value)| (String::from(name), String::from(value)))
+            .collect();
+            let ids = from_tags(&tags);
+            assert_eq!(ids.len(), 3);
+            for id in &[doi("10.1000/abc"), arxiv("2101.01234"), isbn("0201038013")] {
+                assert!(ids.contains(id));
+            }
+        }
+
+        #[test]
+        fn scanning_documents_other_than_pdf_only_uses_the_file_name() {
+            let scan = scan_document(Path::new("/nonexistent/10.1000_xyz.txt"), None);
+            assert_eq!(scan.own, [doi("10.1000/xyz")]);
+            assert!(scan.candidates.is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resolve::CachedResolver;
+    use std::fs;
+
+    /// Answers every identifier with the same record.
+    struct Fixed(&'static str);
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _id: &Identifier) -> Result<String, ResolveError> {
+            Ok(String::from(self.0))
+        }
+    }
+
+    #[test]
+    fn lookup_only_caches_parsable_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Identifier::Doi(String::from("10.1000/xyz"));
+
+        let invalid = CachedResolver::new(Some(Fixed("<html>")), dir.path().to_path_buf());
+        assert!(lookup(std::slice::from_ref(&id), &invalid, |_| true).is_none());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let record = "@article{doe2020, author={Doe, John}, title={A title}, year={2020}}";
+        let valid = CachedResolver::new(Some(Fixed(record)), dir.path().to_path_buf());
+        let results = lookup(std::slice::from_ref(&id), &valid, |_| true).unwrap();
+        assert_eq!(results[0].identifiers(), std::slice::from_ref(&id));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Records of other documents are cached but not used
+        let other = CachedResolver::new(Some(Fixed(record)), dir.path().join("other"));
+        assert!(lookup(&[id], &other, |m| m.title() == "Another title").is_none());
+        assert_eq!(fs::read_dir(dir.path().join("other")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn lookup_replaces_keys_which_are_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let record =
+            "@article{https://doi.org/10.1000/xyz, author={Doe, John}, title={T}, year={2020}}";
+        let resolver = CachedResolver::new(Some(Fixed(record)), dir.path().to_path_buf());
+        let id = Identifier::Doi(String::from("10.1000/xyz"));
+        let results = lookup(&[id], &resolver, |_| true).unwrap();
+        assert_eq!(results[0].key(), "doe2020");
+        assert!(results[0].has_generated_key());
+    }
+
+    #[test]
+    fn import_is_undone_if_the_library_cannot_be_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "move_files: true");
+        let mut lib = Library::new(conf.variables().library_location());
+        // Storing the library fails as its temporary file cannot be created
+        fs::create_dir(dir.path().join("library.json.tmp")).unwrap();
+
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = source_dir.path().join("paper.pdf");
+        fs::write(&source, "document").unwrap();
+        let digest = digest::calculate(&source).unwrap();
+        let meta = LibraryEntryMeta::new(
+            String::from("doe2020"),
+            LibraryEntryType::Article,
+            String::from("A title"),
+            vec![String::from("Doe, John")],
+            2020,
+            None,
+            None,
+        );
+        let options = ImportOptions {
+            tags: vec![String::from("physics/optics"), String::from("reading")],
+            ..ImportOptions::default()
+        };
+
+        let result = import_digested(&source, digest, vec![meta], None, &options, &mut lib, &conf);
+        assert!(result.is_err());
+        assert!(lib.entries().is_empty());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "document");
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["library.json.tmp"]);
+        // The library is stored once it is dropped
+        fs::remove_dir(dir.path().join("library.json.tmp")).unwrap();
+    }
+}
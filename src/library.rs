@@ -1,8 +1,9 @@
 //! Handles loading and storing of the metadata library as well as queries.
 
 use configuration::Configuration;
-use model::LibraryEntry;
-use regex::Regex;
+use model::{FileDigest, Identifier, LibraryEntry, LibraryEntryMeta};
+use query;
+use query::{Condition, Field, Query};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 use std::fs::File;
@@ -28,6 +29,15 @@ quick_error! {
     }
 }
 
+impl From<LibraryPersistenceError> for std::io::Error {
+    fn from(err: LibraryPersistenceError) -> std::io::Error {
+        match err {
+            LibraryPersistenceError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum QueryError {
@@ -36,6 +46,14 @@ quick_error! {
             display(self_) -> ("Invalid regex: {}", err)
             from()
         }
+        /// Returned when a query expression is malformed
+        Syntax(descr: String, position: usize) {
+            display(self_) -> ("Invalid query at character {}: {}", position, descr)
+        }
+        /// Returned when an operation affecting the matching entries is given no query
+        NoFilter {
+            display(self_) -> ("No query given, refusing to select all documents.")
+        }
         /// Returned when no match was found for a query
         NoMatch {
             display(self_) -> ("No match found for query.")
@@ -69,13 +87,29 @@ pub struct Library {
     changed: bool,
 }
 
+/// Describes why an entry of the library is considered a duplicate of an imported document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Duplicate {
+    /// The files have the same digest
+    Digest,
+    /// The entries have the same citation key
+    Key,
+    /// The entries share a persistent identifier like a DOI
+    Identifier(Identifier),
+}
+
+/// Regular expressions and a query expression used to filter library entries. The year may also
+/// be a comparison or range like `>2018` or `2015..2019`. Entries have to match every given
+/// expression, the matching is case insensitive.
 #[derive(Debug, Clone)]
 pub struct QueryParams<'a> {
     author: Option<&'a str>,
     year: Option<&'a str>,
     title: Option<&'a str>,
     doc_type: Option<&'a str>,
+    tag: Option<&'a str>,
     general: Option<&'a str>,
+    expression: Option<&'a str>,
 }
 
 impl FromStr for VersionSpec {
@@ -128,6 +162,67 @@ impl Default for LibraryFile {
     }
 }
 
+impl<'a> QueryParams<'a> {
+    pub fn new(
+        author: Option<&'a str>,
+        year: Option<&'a str>,
+        title: Option<&'a str>,
+        doc_type: Option<&'a str>,
+        tag: Option<&'a str>,
+        general: Option<&'a str>,
+        expression: Option<&'a str>,
+    ) -> QueryParams<'a> {
+        QueryParams {
+            author,
+            year,
+            title,
+            doc_type,
+            tag,
+            general,
+            expression,
+        }
+    }
+
+    /// Combines the regular expressions and the query expression into a single query, or
+    /// returns `None` if no parameter is given.
+    pub fn to_query(&self) -> Result<Option<Query>, QueryError> {
+        let regexes = [
+            (Field::Author, self.author),
+            (Field::Title, self.title),
+            (Field::Type, self.doc_type),
+            (Field::Tag, self.tag),
+            (Field::Any, self.general),
+        ];
+        let mut queries = Vec::new();
+        if let Some(y) = self.year {
+            queries.push(Query::Condition(
+                Field::Year,
+                query::field_condition(Field::Year, y)?,
+            ));
+        }
+        for (field, pattern) in regexes.iter() {
+            if let Some(p) = pattern {
+                queries.push(Query::Condition(*field, Condition::Regex(query::regex(p)?)));
+            }
+        }
+        if let Some(e) = self.expression {
+            queries.push(Query::parse(e)?);
+        }
+
+        Ok(queries.into_iter().reduce(Query::and))
+    }
+}
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Duplicate::Digest => f.write_str("identical file"),
+            Duplicate::Key => f.write_str("same citation key"),
+            Duplicate::Identifier(id) => write!(f, "same identifier {}", id),
+        }
+    }
+}
+
 impl Drop for Library {
     fn drop(&mut self) {
         // store the new state of the library if it was changed
@@ -146,104 +241,136 @@ impl Library {
         }
     }
 
-    pub fn add_entry(&mut self, entry: LibraryEntry) {
-        self.content.entries.push(entry);
-        self.changed = true;
+    pub fn entries(&self) -> &[LibraryEntry] {
+        self.content.entries.as_slice()
+    }
+
+    /// Returns `key` if no entry uses it yet or else the first free key formed by appending
+    /// letters like `doe2020a`, `doe2020b` and so on.
+    pub fn unique_key(&self, key: &str) -> String {
+        let taken = |k: &str| self.content.entries.iter().any(|e| e.meta().key() == k);
+        if !taken(key) {
+            return String::from(key);
+        }
+
+        (0..)
+            .map(|mut n: usize| {
+                // The suffixes continue with aa, ab, ... after z
+                let mut suffix = String::new();
+                loop {
+                    suffix.insert(0, (b'a' + (n % 26) as u8) as char);
+                    if n < 26 {
+                        break;
+                    }
+                    n = n / 26 - 1;
+                }
+                format!("{}{}", key, suffix)
+            })
+            .find(|k| !taken(k))
+            .unwrap_or_default()
     }
 
+    /// Searches for an entry which is a duplicate of a document with the given metadata and
+    /// digest. Identical files are preferred over matching keys or identifiers.
+    pub fn find_duplicate(
+        &self,
+        meta: &LibraryEntryMeta,
+        digest: &FileDigest,
+    ) -> Option<(usize, Duplicate)> {
+        let entries = &self.content.entries;
+        if let Some(i) = entries.iter().position(|e| e.digest() == digest) {
+            return Some((i, Duplicate::Digest));
+        }
+        if let Some(i) = entries.iter().position(|e| e.meta().key() == meta.key()) {
+            return Some((i, Duplicate::Key));
+        }
+        for id in meta.identifiers() {
+            if let Some(i) = entries
+                .iter()
+                .position(|e| e.meta().identifiers().contains(id))
+            {
+                return Some((i, Duplicate::Identifier(id.clone())));
+            }
+        }
+
+        None
+    }
+
+    /// Removes all entries matching the query parameters from the library after the callback
+    /// confirmed the removal and returns the number of removed entries.
+    ///
+    /// If `remove_file` is set, every file path of an entry (including all hard-linked copies)
+    /// is deleted as well. An entry is only removed from the library once all of its files
+    /// were deleted, so that no file is left behind without being tracked. At least one query
+    /// parameter has to be given.
     pub fn remove_entry<F: Fn(Vec<&LibraryEntry>) -> bool>(
         &mut self,
         query_params: &QueryParams,
         remove_file: bool,
         confirm_callback: F,
-    ) -> Result<(), QueryError> {
-        let mut query_results = self.query(query_params)?;
+    ) -> Result<usize, QueryError> {
+        // An empty query matches all entries, which is never meant when removing documents
+        if query_params.to_query()?.is_none() {
+            return Err(QueryError::NoFilter);
+        }
+        let mut query_results = self.query_indices(query_params)?;
         let query_entries: Vec<&LibraryEntry> = query_results
             .iter()
             .map(|&i| &self.content.entries[i])
             .collect();
-        if confirm_callback(query_entries) {
-            query_results.sort_unstable_by(|a, b| a.cmp(b).reverse());
-            for i in query_results {
-                self.content.entries.remove(i);
-                if remove_file {
-                    for f in self.content.entries[i].file_paths() {
-                        std::fs::remove_file(f)?;
-                    }
+        if !confirm_callback(query_entries) {
+            return Ok(0);
+        }
+
+        // Remove the entries back to front so the remaining indices stay valid
+        query_results.sort_unstable_by(|a, b| a.cmp(b).reverse());
+        let mut removed = 0;
+        let mut first_error = None;
+        for i in query_results {
+            if remove_file {
+                if let Err(e) = remove_files(self.content.entries[i].file_paths()) {
+                    first_error.get_or_insert(e);
+                    continue;
                 }
             }
+            self.content.entries.remove(i);
             self.changed = true;
-            Ok(())
-        } else {
-            Ok(())
+            removed += 1;
+        }
+
+        match first_error {
+            Some(e) => Err(QueryError::from(e)),
+            None => Ok(removed),
         }
     }
+
+    /// Search for library entries matching the query parameters.
+    pub fn query(&self, params: &QueryParams) -> Result<Vec<&LibraryEntry>, QueryError> {
+        Ok(self
+            .query_indices(params)?
+            .into_iter()
+            .map(|i| &self.content.entries[i])
+            .collect())
+    }
+
     /// Search for library entries matching the query parameters and return a list of
     /// their indices.
-    fn query(&self, params: &QueryParams) -> Result<Vec<usize>, QueryError> {
-        let mut results: Vec<usize> = Vec::new();
-        let author_regex = if let Some(p) = params.author {
-            Some(Regex::new(p)?)
-        } else {
-            None
-        };
-        let year_regex = if let Some(p) = params.year {
-            Some(Regex::new(p)?)
-        } else {
-            None
-        };
-        let title_regex = if let Some(p) = params.title {
-            Some(Regex::new(p)?)
-        } else {
-            None
-        };
-        let type_regex = if let Some(p) = params.title {
-            Some(Regex::new(p)?)
-        } else {
-            None
-        };
-        let general_regex = if let Some(p) = params.general {
-            Some(Regex::new(p)?)
-        } else {
-            None
-        };
-        for i in 0..self.content.entries.len() {
-            let meta = &self.content.entries[i].meta();
-            if let Some(r) = author_regex.as_ref() {
-                if !meta.authors().iter().any(|a| r.is_match(a)) {
-                    continue;
-                }
-            }
-            if let Some(r) = year_regex.as_ref() {
-                if !r.is_match(&meta.year().to_string()) {
-                    continue;
-                }
-            }
-            if let Some(r) = title_regex.as_ref() {
-                if !r.is_match(meta.title()) {
-                    continue;
-                }
-            }
-            if let Some(r) = type_regex.as_ref() {
-                if !r.is_match(&meta.entry_type().to_string()) {
-                    continue;
-                }
-            }
+    pub fn query_indices(&self, params: &QueryParams) -> Result<Vec<usize>, QueryError> {
+        let query = params.to_query()?;
+        let results: Vec<usize> = self
+            .content
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| query.as_ref().is_none_or(|q| q.matches(e)))
+            .map(|(i, _)| i)
+            .collect();
 
-            if let Some(r) = general_regex.as_ref() {
-                if !(meta.authors().iter().any(|a| r.is_match(a))
-                    || r.is_match(meta.title())
-                    || r.is_match(&meta.year().to_string())
-                    || r.is_match(meta.title())
-                    || r.is_match(&meta.entry_type().to_string()))
-                {
-                    continue;
-                }
-            }
-            results.push(i);
+        if results.is_empty() {
+            Err(QueryError::NoMatch)
+        } else {
+            Ok(results)
         }
-
-        Ok(results)
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Library, LibraryPersistenceError> {
@@ -257,11 +384,84 @@ impl Library {
         })
     }
 
+    /// Writes the library to a temporary file first and replaces the library file with it, so
+    /// the library file is never left half-written.
     pub fn store(&self) -> Result<(), LibraryPersistenceError> {
-        serde_json::to_writer(File::create(&self.path)?, &self.content)?;
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let file = File::create(&temp)?;
+        serde_json::to_writer(&file, &self.content)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
 
         Ok(())
     }
+
+    /// Adds an entry and stores the library right away. The entry is removed again if storing
+    /// the library fails.
+    pub fn add_entry_stored(&mut self, entry: LibraryEntry) -> Result<(), LibraryPersistenceError> {
+        self.content.entries.push(entry);
+        match self.store() {
+            Ok(()) => {
+                self.changed = false;
+                Ok(())
+            }
+            Err(e) => {
+                self.content.entries.pop();
+                Err(e)
+            }
+        }
+    }
+
+    /// Replaces the entry with the given index and stores the library right away. The old entry
+    /// is restored if storing the library fails.
+    pub fn replace_entry_stored(
+        &mut self,
+        index: usize,
+        entry: LibraryEntry,
+    ) -> Result<(), LibraryPersistenceError> {
+        self.replace_entries_stored(vec![(index, entry)])
+    }
+
+    /// Replaces the entries with the given indices and stores the library right away. The old
+    /// entries are restored if storing the library fails.
+    pub fn replace_entries_stored(
+        &mut self,
+        entries: Vec<(usize, LibraryEntry)>,
+    ) -> Result<(), LibraryPersistenceError> {
+        let old: Vec<(usize, LibraryEntry)> = entries
+            .into_iter()
+            .map(|(i, entry)| (i, std::mem::replace(&mut self.content.entries[i], entry)))
+            .collect();
+        match self.store() {
+            Ok(()) => {
+                self.changed = false;
+                Ok(())
+            }
+            Err(e) => {
+                // Restored in reverse order in case an entry was replaced more than once
+                for (i, entry) in old.into_iter().rev() {
+                    self.content.entries[i] = entry;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Deletes all given files, ignoring files which do not exist anymore.
+fn remove_files(paths: &[String]) -> Result<(), std::io::Error> {
+    for p in paths {
+        match std::fs::remove_file(p) {
+            Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(std::io::Error::new(e.kind(), format!("{}: {}", p, e)))
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
 }
 
 pub fn load_from_cfg(conf: &Configuration) -> Result<Library, LibraryPersistenceError> {
@@ -273,3 +473,41 @@ pub fn load_from_cfg(conf: &Configuration) -> Result<Library, LibraryPersistence
         Library::load(path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::LibraryEntryType;
+
+    fn entry(key: &str) -> LibraryEntry {
+        let meta = LibraryEntryMeta::new(
+            String::from(key),
+            LibraryEntryType::Article,
+            String::from("Title"),
+            vec![String::from("Doe, John")],
+            2020,
+            None,
+            None,
+        );
+        LibraryEntry::new(meta, Vec::new(), Vec::new(), FileDigest::default())
+    }
+
+    #[test]
+    fn remove_entry_requires_a_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut lib = Library::new(dir.path().join("library.json"));
+        lib.add_entry_stored(entry("doe2020")).unwrap();
+        lib.add_entry_stored(entry("roe2019")).unwrap();
+
+        let none = QueryParams::new(None, None, None, None, None, None, None);
+        assert!(matches!(
+            lib.remove_entry(&none, false, |_| true),
+            Err(QueryError::NoFilter)
+        ));
+        assert_eq!(lib.entries().len(), 2);
+
+        let key = QueryParams::new(None, None, None, None, None, None, Some("key:=doe2020"));
+        assert_eq!(lib.remove_entry(&key, false, |_| true).unwrap(), 1);
+        assert_eq!(lib.entries()[0].meta().key(), "roe2019");
+    }
+}
@@ -5,24 +5,40 @@ extern crate sha2;
 extern crate quick_error;
 #[macro_use]
 extern crate serde_derive;
+extern crate nom;
+extern crate nom_bibtex;
 extern crate serde;
 extern crate serde_json;
 extern crate serde_yaml;
-extern crate nom;
-extern crate nom_bibtex;
 #[macro_use]
 extern crate lazy_static;
 extern crate directories;
 #[macro_use]
 extern crate clap;
 extern crate hex;
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate lopdf;
+extern crate pdf_extract;
 extern crate regex;
+extern crate tempfile;
+extern crate ureq;
 
+mod batch;
 mod cli;
 mod configuration;
+mod digest;
+mod editor;
+mod fsck;
 mod import;
 mod library;
 mod model;
+mod query;
+mod rename;
+mod resolve;
+mod storage;
+mod tags;
+mod views;
 
 use cli::process_args;
 use configuration::Configuration;
@@ -34,8 +50,14 @@ fn main() {
         Ok(l) => l,
         Err(e) => {
             eprintln!("Failed to load library: {}", e);
-            return;
+            std::process::exit(1);
         }
     };
-    process_args(&conf, &mut lib);
+    let code = process_args(&conf, &mut lib);
+
+    // Exiting does not run destructors, so the library and configuration have to be stored
+    // beforehand
+    drop(lib);
+    drop(conf);
+    std::process::exit(code);
 }
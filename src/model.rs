@@ -42,8 +42,9 @@ pub enum LibraryEntryType {
     Unpublished,
 }
 
-/// An enum expressing a month and providing various conversion functions
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// An enum expressing a month and providing various conversion functions. Months are ordered
+/// chronologically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Month {
     Jan,
     Feb,
@@ -59,6 +60,14 @@ pub enum Month {
     Dec,
 }
 
+/// A persistent identifier of a document which can be used to look up its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Identifier {
+    Doi(String),
+    Arxiv(String),
+    Isbn(String),
+}
+
 /// The type used for representing file digests, though not the actual type stored in the
 /// database.
 pub type FileDigest = GenericArray<u8, <Sha256 as FixedOutput>::OutputSize>;
@@ -77,6 +86,12 @@ pub struct LibraryEntryMeta {
     year: u32,
     month: Option<Month>,
     original_tags: Option<TagMap>,
+    #[serde(default)]
+    identifiers: Vec<Identifier>,
+    /// Whether the key was generated instead of taken from a bibliography, in which case it
+    /// may be changed to keep keys unique
+    #[serde(skip)]
+    generated_key: bool,
 }
 
 /// A structure containing the metadata and file information of an entry stored in the
@@ -143,6 +158,35 @@ impl fmt::Display for LibraryEntryType {
     }
 }
 
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Identifier::Doi(doi) => write!(f, "doi:{}", doi),
+            Identifier::Arxiv(id) => write!(f, "arXiv:{}", id),
+            Identifier::Isbn(isbn) => write!(f, "ISBN:{}", isbn),
+        }
+    }
+}
+
+impl Identifier {
+    /// Creates a DOI identifier, normalizing it to lower case since DOIs are case
+    /// insensitive.
+    pub fn doi(doi: &str) -> Identifier {
+        let doi = doi.trim();
+        let doi = ["https://doi.org/", "http://dx.doi.org/", "doi:"]
+            .iter()
+            .find_map(|prefix| {
+                if doi.to_lowercase().starts_with(prefix) {
+                    doi.get(prefix.len()..)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(doi);
+        Identifier::Doi(doi.to_lowercase())
+    }
+}
+
 impl LibraryEntryMeta {
     pub fn new(
         key: String,
@@ -161,12 +205,24 @@ impl LibraryEntryMeta {
             year,
             month,
             original_tags,
+            identifiers: Vec::new(),
+            generated_key: false,
         }
     }
     pub fn key(&self) -> &str {
         &self.key
     }
 
+    /// Replaces the key by a generated one.
+    pub fn set_generated_key(&mut self, key: String) {
+        self.key = key;
+        self.generated_key = true;
+    }
+
+    pub fn has_generated_key(&self) -> bool {
+        self.generated_key
+    }
+
     pub fn entry_type(&self) -> LibraryEntryType {
         self.entry_type
     }
@@ -190,6 +246,17 @@ impl LibraryEntryMeta {
     pub fn original_tags(&self) -> Option<&TagMap> {
         self.original_tags.as_ref()
     }
+
+    pub fn identifiers(&self) -> &[Identifier] {
+        self.identifiers.as_slice()
+    }
+
+    /// Adds an identifier to the entry unless it is already known.
+    pub fn add_identifier(&mut self, identifier: Identifier) {
+        if !self.identifiers.contains(&identifier) {
+            self.identifiers.push(identifier);
+        }
+    }
 }
 
 impl LibraryEntry {
@@ -222,6 +289,25 @@ impl LibraryEntry {
     pub fn digest(&self) -> &FileDigest {
         &self.digest
     }
+
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.tags = tags;
+    }
+
+    pub fn set_file_paths(&mut self, file_paths: Vec<String>) {
+        self.file_paths = file_paths;
+    }
+
+    /// Replaces the file path with the given index.
+    pub fn set_file_path(&mut self, index: usize, path: String) {
+        self.file_paths[index] = path;
+    }
+
+    /// Adds a tag along with the path of the file linked to the tag.
+    pub fn add_tag(&mut self, tag: String, path: String) {
+        self.tags.push(tag);
+        self.file_paths.push(path);
+    }
 }
 
 impl Month {
@@ -0,0 +1,721 @@
+//! Parses and evaluates boolean query expressions like
+//! `author:knuth and (year:>=2010 or tag:survey) and not type:misc`.
+//!
+//! An expression consists of conditions combined with `and`, `or`, `not` and parentheses.
+//! Conditions written next to each other without an operator all have to match. A condition
+//! is a value optionally prefixed by a field and an operator:
+//!
+//! * `field:value` matches if the regular expression `value` matches the field
+//! * `field:=value` matches if the field equals `value`
+//! * `field:>value`, `field:>=value`, `field:<value` and `field:<=value` compare the field to
+//!   `value`, alphabetically for all fields but the year and month
+//! * `year:2015..2019` and `month:mar..jun` match years and months within a range including
+//!   both ends, either end may be left out
+//!
+//! Years and months are compared by their value, months can be given by number or name. A
+//! `month` value which is a month like `month:3` or `month:march` matches that month, other
+//! values are regular expressions matched against the name of the month.
+//!
+//! Values without a field are matched against all fields. Values containing whitespace,
+//! parentheses or colons can be enclosed in double quotes. All matching is case insensitive.
+
+use library::QueryError;
+use model::{LibraryEntry, Month};
+use regex::{Regex, RegexBuilder};
+use std::cmp::Ordering;
+use std::fmt;
+use tags;
+
+/// The fields of a library entry a condition can refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Author,
+    Title,
+    Year,
+    Month,
+    Type,
+    Tag,
+    Key,
+    /// All of the above but the month
+    Any,
+}
+
+/// The comparison operators supported for conditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A value a field is compared to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    Year(u32),
+    Month(Month),
+    /// A text in lower case
+    Text(String),
+}
+
+/// A condition a field of an entry has to fulfill.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Regex(Regex),
+    /// The field has to equal the text, which is stored in lower case
+    Exact(String),
+    Compare(Comparison, Value),
+    /// The year or month has to be within the range including both ends
+    Range(Value, Value),
+}
+
+/// The syntax tree of a query expression.
+#[derive(Debug, Clone)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Condition(Field, Condition),
+}
+
+/// A token of a query expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    OpenParen,
+    CloseParen,
+    And,
+    Or,
+    Not,
+    /// A word with its quotes removed, together with the index of the first character which
+    /// was quoted, if any
+    Word(String, Option<usize>),
+}
+
+impl Field {
+    const NAMES: &'static [(&'static str, Field)] = &[
+        ("author", Field::Author),
+        ("title", Field::Title),
+        ("year", Field::Year),
+        ("month", Field::Month),
+        ("type", Field::Type),
+        ("tag", Field::Tag),
+        ("key", Field::Key),
+    ];
+
+    fn from_name(name: &str) -> Option<Field> {
+        let name = name.to_lowercase();
+        Field::NAMES
+            .iter()
+            .find(|(n, _)| *n == name || format!("{}s", n) == name)
+            .map(|&(_, f)| f)
+    }
+
+    /// Returns the values of this field of an entry. Authors and tags may have many values,
+    /// tags include their parents.
+    fn values(self, entry: &LibraryEntry) -> Vec<String> {
+        let meta = entry.meta();
+        match self {
+            Field::Author => meta.authors().clone(),
+            Field::Title => vec![meta.title().to_string()],
+            Field::Year => vec![meta.year().to_string()],
+            Field::Month => meta.month().iter().map(Month::to_string).collect(),
+            Field::Type => vec![meta.entry_type().to_string()],
+            Field::Tag => entry
+                .tags()
+                .iter()
+                .flat_map(|t| tags::ancestors(t))
+                .map(String::from)
+                .collect(),
+            Field::Key => vec![meta.key().to_string()],
+            Field::Any => [
+                Field::Author,
+                Field::Title,
+                Field::Year,
+                Field::Type,
+                Field::Tag,
+                Field::Key,
+            ]
+            .iter()
+            .flat_map(|f| f.values(entry))
+            .collect(),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match Field::NAMES.iter().find(|(_, field)| field == self) {
+            Some((name, _)) => f.write_str(name),
+            None => f.write_str("any field"),
+        }
+    }
+}
+
+impl Comparison {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl Value {
+    /// Compares the year or month of an entry to the value. Returns `None` for texts and
+    /// entries without a month.
+    fn compare(&self, entry: &LibraryEntry) -> Option<Ordering> {
+        match self {
+            Value::Year(year) => Some(entry.meta().year().cmp(year)),
+            Value::Month(month) => entry.meta().month().map(|m| m.cmp(month)),
+            Value::Text(_) => None,
+        }
+    }
+}
+
+impl Query {
+    /// Parses a query expression.
+    pub fn parse(expression: &str) -> Result<Query, QueryError> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            expression,
+            tokens,
+            next: 0,
+        };
+        if parser.tokens.is_empty() {
+            return Err(parser.error(0, "the query is empty"));
+        }
+        let query = parser.parse_or()?;
+        match parser.tokens.get(parser.next) {
+            Some(&(Token::CloseParen, pos)) => Err(parser.error(pos, "`)` without matching `(`")),
+            Some(&(_, pos)) => Err(parser.error(pos, "expected `and` or `or`")),
+            None => Ok(query),
+        }
+    }
+
+    /// Combines two queries, both of which have to match.
+    pub fn and(self, other: Query) -> Query {
+        Query::And(Box::new(self), Box::new(other))
+    }
+
+    /// Whether an entry matches the query.
+    pub fn matches(&self, entry: &LibraryEntry) -> bool {
+        match self {
+            Query::And(a, b) => a.matches(entry) && b.matches(entry),
+            Query::Or(a, b) => a.matches(entry) || b.matches(entry),
+            Query::Not(q) => !q.matches(entry),
+            Query::Condition(field, condition) => condition.matches(*field, entry),
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, field: Field, entry: &LibraryEntry) -> bool {
+        match self {
+            Condition::Regex(r) => field.values(entry).iter().any(|v| r.is_match(v)),
+            Condition::Exact(text) => field
+                .values(entry)
+                .iter()
+                .any(|v| v.to_lowercase() == *text),
+            Condition::Compare(cmp, Value::Text(text)) => field
+                .values(entry)
+                .iter()
+                .any(|v| cmp.holds(v.to_lowercase().as_str().cmp(text))),
+            Condition::Compare(cmp, value) => value.compare(entry).is_some_and(|o| cmp.holds(o)),
+            Condition::Range(low, high) => {
+                low.compare(entry).is_some_and(|o| o != Ordering::Less)
+                    && high.compare(entry).is_some_and(|o| o != Ordering::Greater)
+            }
+        }
+    }
+}
+
+/// Compiles a case insensitive regular expression as used by all queries.
+pub fn regex(pattern: &str) -> Result<Regex, QueryError> {
+    Ok(RegexBuilder::new(pattern).case_insensitive(true).build()?)
+}
+
+/// The operators which may follow the field of a condition, longer operators first.
+const OPERATORS: &[(&str, Option<Comparison>)] = &[
+    (">=", Some(Comparison::GreaterOrEqual)),
+    ("<=", Some(Comparison::LessOrEqual)),
+    (">", Some(Comparison::Greater)),
+    ("<", Some(Comparison::Less)),
+    ("=", None),
+];
+
+/// Returns an error for an invalid condition. The position is corrected by the parser if the
+/// condition is part of an expression.
+fn invalid(descr: String) -> QueryError {
+    QueryError::Syntax(descr, 1)
+}
+
+/// Parses the value of a year or month, other values are texts in lower case.
+fn value(field: Field, value: &str) -> Result<Value, QueryError> {
+    let value = value.trim();
+    match field {
+        Field::Year => value
+            .parse()
+            .map(Value::Year)
+            .map_err(|_| invalid(format!("`{}` is not a valid year", value))),
+        Field::Month => value
+            .parse()
+            .map(Value::Month)
+            .map_err(|_| invalid(format!("`{}` is not a valid month", value))),
+        _ => Ok(Value::Text(value.to_lowercase())),
+    }
+}
+
+/// Builds the condition of a field from an operator as in `OPERATORS` and a value.
+fn condition(field: Field, operator: Option<&str>, text: &str) -> Result<Condition, QueryError> {
+    let typed = field == Field::Year || field == Field::Month;
+    let comparison = match OPERATORS.iter().find(|(op, _)| Some(*op) == operator) {
+        Some((_, Some(cmp))) => *cmp,
+        Some((_, None)) if typed => Comparison::Equal,
+        Some((_, None)) => return Ok(Condition::Exact(text.to_lowercase())),
+        None if typed && is_range(field, text) => return range(field, text),
+        None if field == Field::Month && text.chars().all(char::is_alphanumeric) => {
+            match text.parse() {
+                Ok(month) => return Ok(Condition::Compare(Comparison::Equal, Value::Month(month))),
+                Err(_) if text.chars().all(|c| c.is_ascii_digit()) => {
+                    return Err(invalid(format!("`{}` is not a valid month", text)))
+                }
+                Err(_) => return Ok(Condition::Regex(regex(text)?)),
+            }
+        }
+        None => return Ok(Condition::Regex(regex(text)?)),
+    };
+    if field == Field::Any {
+        return Err(invalid(String::from(
+            "comparisons require a field like `year:>2010`",
+        )));
+    }
+
+    Ok(Condition::Compare(comparison, value(field, text)?))
+}
+
+/// Whether a value is a range like `2015..` whose bounds are valid values of the field. Other
+/// values containing `..` like `19..` are regular expressions.
+fn is_range(field: Field, text: &str) -> bool {
+    let bounds: Vec<&str> = text
+        .splitn(2, "..")
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .collect();
+    text.contains("..")
+        && !bounds.is_empty()
+        && bounds.iter().all(|b| match field {
+            // Shorter numbers are rather parts of a regular expression like `19..` for a century
+            Field::Year => b.len() == 4 && b.chars().all(|c| c.is_ascii_digit()),
+            _ => value(field, b).is_ok(),
+        })
+}
+
+/// Builds the condition for a range like `2015..2019`, `2015..` or `..2019`.
+fn range(field: Field, text: &str) -> Result<Condition, QueryError> {
+    let (low, high) = text.split_once("..").unwrap_or((text, ""));
+    match (low.trim().is_empty(), high.trim().is_empty()) {
+        (true, true) => Err(invalid(String::from("the range has no bounds"))),
+        (false, true) => Ok(Condition::Compare(
+            Comparison::GreaterOrEqual,
+            value(field, low)?,
+        )),
+        (true, false) => Ok(Condition::Compare(
+            Comparison::LessOrEqual,
+            value(field, high)?,
+        )),
+        (false, false) => {
+            let (low, high) = (value(field, low)?, value(field, high)?);
+            if low > high {
+                return Err(invalid(format!("the range {} is empty", text)));
+            }
+            Ok(Condition::Range(low, high))
+        }
+    }
+}
+
+/// Parses a condition for a field given without the field name, like `>2018` or
+/// `2015..2019` for the year.
+pub fn field_condition(field: Field, text: &str) -> Result<Condition, QueryError> {
+    match OPERATORS.iter().find(|(op, _)| text.starts_with(op)) {
+        Some((op, _)) => condition(field, Some(op), &text[op.len()..]),
+        None => condition(field, None, text),
+    }
+}
+
+/// Returns the position of a byte index in an expression as a character count starting at 1.
+fn position(expression: &str, index: usize) -> usize {
+    expression[..index].chars().count() + 1
+}
+
+/// Splits an expression into tokens along with their byte indices.
+fn tokenize(expression: &str) -> Result<Vec<(Token, usize)>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '(' || c == ')' {
+            chars.next();
+            let token = if c == '(' {
+                Token::OpenParen
+            } else {
+                Token::CloseParen
+            };
+            tokens.push((token, start));
+            continue;
+        }
+
+        let mut word = String::new();
+        let mut first_quoted = None;
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                break;
+            }
+            chars.next();
+            if c != '"' {
+                word.push(c);
+                continue;
+            }
+            first_quoted.get_or_insert(word.len());
+            // A backslash escapes quotes and backslashes inside of quotes
+            let mut closed = false;
+            while let Some((_, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    '\\' if chars.peek().is_some_and(|&(_, n)| n == '"' || n == '\\') => {
+                        if let Some((_, n)) = chars.next() {
+                            word.push(n);
+                        }
+                    }
+                    c => word.push(c),
+                }
+            }
+            if !closed {
+                return Err(QueryError::Syntax(
+                    String::from("unterminated `\"`"),
+                    position(expression, i),
+                ));
+            }
+        }
+
+        let token = match (word.to_lowercase().as_str(), first_quoted) {
+            ("and", None) => Token::And,
+            ("or", None) => Token::Or,
+            ("not", None) => Token::Not,
+            _ => Token::Word(word, first_quoted),
+        };
+        tokens.push((token, start));
+    }
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser for the tokens of an expression. `or` binds weaker than `and`,
+/// which binds weaker than `not`.
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, index: usize, descr: &str) -> QueryError {
+        QueryError::Syntax(String::from(descr), position(self.expression, index))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(t, _)| t)
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            query = Query::Or(Box::new(query), Box::new(self.parse_and()?));
+        }
+
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.next += 1,
+                Some(Token::Not) | Some(Token::OpenParen) | Some(Token::Word(..)) => (),
+                _ => break,
+            }
+            query = query.and(self.parse_not()?);
+        }
+
+        Ok(query)
+    }
+
+    fn parse_not(&mut self) -> Result<Query, QueryError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next += 1;
+            return Ok(Query::Not(Box::new(self.parse_not()?)));
+        }
+
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, QueryError> {
+        let (token, index) = match self.tokens.get(self.next) {
+            Some((t, i)) => (t.clone(), *i),
+            None => {
+                return Err(self.error(
+                    self.expression.len(),
+                    "unexpected end of the query, expected a condition",
+                ))
+            }
+        };
+        self.next += 1;
+
+        match token {
+            Token::OpenParen => {
+                let query = self.parse_or()?;
+                if self.peek() != Some(&Token::CloseParen) {
+                    let at = self
+                        .tokens
+                        .get(self.next)
+                        .map_or(self.expression.len(), |&(_, i)| i);
+                    return Err(self.error(
+                        at,
+                        &format!(
+                            "expected `)` closing the `(` at character {}",
+                            position(self.expression, index)
+                        ),
+                    ));
+                }
+                self.next += 1;
+                Ok(query)
+            }
+            Token::CloseParen => Err(self.error(index, "expected a condition before `)`")),
+            Token::And => Err(self.error(index, "expected a condition before `and`")),
+            Token::Or => Err(self.error(index, "expected a condition before `or`")),
+            Token::Not => Err(self.error(index, "expected a condition after `not`")),
+            Token::Word(word, first_quoted) => self.parse_condition(&word, first_quoted, index),
+        }
+    }
+
+    /// Parses a condition like `year:>=2010` from a word.
+    fn parse_condition(
+        &self,
+        word: &str,
+        first_quoted: Option<usize>,
+        index: usize,
+    ) -> Result<Query, QueryError> {
+        let unquoted = &word[..first_quoted.unwrap_or(word.len())];
+        let (field, rest) = match unquoted.find(':') {
+            Some(i) => {
+                let name = &word[..i];
+                let field = Field::from_name(name).ok_or_else(|| {
+                    let names: Vec<&str> = Field::NAMES.iter().map(|(n, _)| *n).collect();
+                    self.error(
+                        index,
+                        &format!(
+                            "unknown field `{}`, expected one of {}",
+                            name,
+                            names.join(", ")
+                        ),
+                    )
+                })?;
+                (field, i + 1)
+            }
+            None => (Field::Any, 0),
+        };
+        let unquoted = &unquoted[rest..];
+        let operator = OPERATORS
+            .iter()
+            .map(|(op, _)| *op)
+            .find(|op| unquoted.starts_with(op));
+        let value = &word[rest + operator.map_or(0, str::len)..];
+        if value.is_empty() && first_quoted.is_none() {
+            return Err(self.error(index, &format!("missing value for {}", field)));
+        }
+        let condition = condition(field, operator, value).map_err(|e| match e {
+            QueryError::Syntax(descr, _) => self.error(index, &descr),
+            e => e,
+        })?;
+
+        Ok(Query::Condition(field, condition))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::{FileDigest, LibraryEntryMeta, LibraryEntryType};
+
+    /// Renders a query with explicit parentheses to make its structure visible. Conditions
+    /// without a field are shown with `*` as field.
+    fn show(query: &Query) -> String {
+        let field = |f: &Field| match f {
+            Field::Any => String::from("*"),
+            f => f.to_string(),
+        };
+        match query {
+            Query::And(a, b) => format!("({} & {})", show(a), show(b)),
+            Query::Or(a, b) => format!("({} | {})", show(a), show(b)),
+            Query::Not(q) => format!("!{}", show(q)),
+            Query::Condition(f, Condition::Regex(r)) => format!("{}~{}", field(f), r.as_str()),
+            Query::Condition(f, Condition::Exact(t)) => format!("{}={}", field(f), t),
+            Query::Condition(f, Condition::Compare(cmp, v)) => {
+                format!("{}{:?}{:?}", field(f), cmp, v)
+            }
+            Query::Condition(f, Condition::Range(l, h)) => {
+                format!("{}:{:?}..{:?}", field(f), l, h)
+            }
+        }
+    }
+
+    fn parse(expression: &str) -> String {
+        show(&Query::parse(expression).unwrap())
+    }
+
+    fn error(expression: &str) -> (String, usize) {
+        match Query::parse(expression) {
+            Err(QueryError::Syntax(descr, position)) => (descr, position),
+            other => panic!("expected a syntax error, got {:?}", other.map(|q| show(&q))),
+        }
+    }
+
+    fn entry() -> LibraryEntry {
+        let meta = LibraryEntryMeta::new(
+            String::from("knuth68"),
+            LibraryEntryType::Book,
+            String::from("The Art of Computer Programming"),
+            vec![String::from("Knuth, Donald")],
+            1968,
+            Some(Month::Mar),
+            None,
+        );
+        LibraryEntry::new(
+            meta,
+            vec![String::from("cs/algorithms")],
+            Vec::new(),
+            FileDigest::default(),
+        )
+    }
+
+    fn matches(expression: &str) -> bool {
+        Query::parse(expression).unwrap().matches(&entry())
+    }
+
+    #[test]
+    fn and_binds_stronger_than_or() {
+        assert_eq!(parse("a or b and c"), "(*~a | (*~b & *~c))");
+        assert_eq!(parse("a and b or c"), "((*~a & *~b) | *~c)");
+        assert_eq!(parse("(a or b) c"), "((*~a | *~b) & *~c)");
+    }
+
+    #[test]
+    fn adjacent_conditions_are_combined_with_and() {
+        assert_eq!(parse("a b"), "(*~a & *~b)");
+        assert_eq!(parse("a AND b"), "(*~a & *~b)");
+    }
+
+    #[test]
+    fn not_binds_strongest() {
+        assert_eq!(parse("not a and b"), "(!*~a & *~b)");
+        assert_eq!(parse("not (a or b)"), "!(*~a | *~b)");
+        assert_eq!(parse("not not a"), "!!*~a");
+    }
+
+    #[test]
+    fn fields_and_operators() {
+        assert_eq!(parse("Authors:knuth"), "author~knuth");
+        assert_eq!(parse("title:=Art"), "title=art");
+        assert_eq!(parse("year:>=2010"), "yearGreaterOrEqualYear(2010)");
+        assert_eq!(parse("month:<jun"), "monthLessMonth(Jun)");
+        assert_eq!(parse("month:3"), "monthEqualMonth(Mar)");
+        assert_eq!(parse("month:^ma"), "month~^ma");
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(parse("title:\"art of\""), "title~art of");
+        assert_eq!(parse("\"a:b\""), "*~a:b");
+        assert_eq!(parse("\"and\""), "*~and");
+        assert_eq!(parse("\"(x)\" or y"), "(*~(x) | *~y)");
+        assert_eq!(parse(r#"title:"say \"hi\"""#), "title~say \"hi\"");
+        assert_eq!(parse("tag:\"\""), "tag~");
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        let (descr, position) = error("title:\"art of");
+        assert_eq!(descr, "unterminated `\"`");
+        assert_eq!(position, 7);
+        assert_eq!(error("a \"b\" \"c").1, 7);
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error("").1, 1);
+        assert_eq!(error("a and").1, 6);
+        assert_eq!(error("a and or b").1, 7);
+        assert_eq!(error("(a or b").1, 8);
+        assert_eq!(error("a )").1, 3);
+        assert_eq!(error("not").1, 4);
+        assert_eq!(error("x foo:bar").1, 3);
+        assert_eq!(
+            error("x year:"),
+            (String::from("missing value for year"), 3)
+        );
+        assert_eq!(error("ä year:>x").1, 3);
+        assert_eq!(error(">2010").1, 1);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse("year:2015..2019"), "year:Year(2015)..Year(2019)");
+        assert_eq!(parse("year:2015.."), "yearGreaterOrEqualYear(2015)");
+        assert_eq!(parse("year:..2019"), "yearLessOrEqualYear(2019)");
+        assert_eq!(parse("month:mar..jun"), "month:Month(Mar)..Month(Jun)");
+        assert_eq!(error("year:2019..2015").0, "the range 2019..2015 is empty");
+    }
+
+    #[test]
+    fn values_which_are_no_ranges_are_regular_expressions() {
+        assert_eq!(parse("year:19.."), "year~19..");
+        assert_eq!(parse("year:.."), "year~..");
+        assert_eq!(parse("month:j..e"), "month~j..e");
+        assert!(matches!(
+            field_condition(Field::Year, "19.."),
+            Ok(Condition::Regex(_))
+        ));
+        assert!(matches!(
+            field_condition(Field::Year, "2015..2019"),
+            Ok(Condition::Range(..))
+        ));
+    }
+
+    #[test]
+    fn matching() {
+        assert!(matches("author:knuth and year:<1970"));
+        assert!(matches("AUTHOR:KNUTH"));
+        assert!(!matches("author:\"(?-i)KNUTH\""));
+        assert!(matches("key:=KNUTH68"));
+        assert!(!matches("key:=knuth"));
+        assert!(matches("tag:=cs and tag:=cs/algorithms"));
+        assert!(matches("not type:article"));
+        assert!(matches("year:1960..1970 month:mar..jun"));
+        assert!(!matches("year:1969.."));
+        assert!(matches("month:march"));
+        assert!(!matches("month:apr"));
+        assert!(matches("year:19.. and \"computer programming\""));
+        assert!(matches("title:>=t and title:<u"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Renames the documents of the library according to the current naming pattern.
+
+use configuration::util::assemble_name;
+use configuration::Configuration;
+use library::Library;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use storage;
+use storage::Transaction;
+
+/// The new paths of the files of an entry.
+#[derive(Debug, Clone)]
+pub struct Rename {
+    pub entry: usize,
+    /// The old and new path of every file of the entry which has to be renamed
+    pub moves: Vec<(String, String)>,
+}
+
+/// Determines the new paths of the files of the given entries. The file name of the first file
+/// of an entry takes the place of the original file name in the pattern. Files keep their
+/// directory and are given unique names never overwriting existing files.
+pub fn plan(lib: &Library, entries: &[usize], conf: &Configuration) -> Vec<Rename> {
+    let mut planned: HashSet<PathBuf> = HashSet::new();
+    let mut renames = Vec::new();
+
+    for &i in entries {
+        let entry = &lib.entries()[i];
+        let first = match entry.file_paths().first() {
+            Some(p) => Path::new(p),
+            None => continue,
+        };
+        let stem = first
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let ext = first
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        let base_name = assemble_name(&stem, entry.meta(), conf);
+        let current: Vec<PathBuf> = entry.file_paths().iter().map(PathBuf::from).collect();
+        let dirs: Vec<PathBuf> = current
+            .iter()
+            .map(|p| p.parent().map(Path::to_path_buf).unwrap_or_default())
+            .collect();
+
+        // The same name is used for all copies of a document
+        let name = (1..)
+            .map(|n| {
+                if n == 1 {
+                    format!("{}{}", base_name, ext)
+                } else {
+                    format!("{}-{}{}", base_name, n, ext)
+                }
+            })
+            .find(|name| {
+                dirs.iter().all(|d| {
+                    let p = d.join(name);
+                    current.contains(&p) || (!p.exists() && !planned.contains(&p))
+                })
+            })
+            .unwrap_or_default();
+
+        let moves: Vec<(String, String)> = current
+            .iter()
+            .zip(dirs.iter())
+            .filter_map(|(old, dir)| {
+                let new = dir.join(&name);
+                if *old == new {
+                    return None;
+                }
+                planned.insert(new.clone());
+                Some((old.to_str()?.to_string(), new.to_str()?.to_string()))
+            })
+            .collect();
+        if !moves.is_empty() {
+            renames.push(Rename { entry: i, moves });
+        }
+    }
+
+    renames
+}
+
+/// Renames the files of an entry and stores the updated library. If renaming any of the files
+/// or storing the library fails, the files already renamed are restored so the entry stays
+/// consistent.
+pub fn apply(lib: &mut Library, rename: &Rename) -> io::Result<()> {
+    let mut transaction = Transaction::new();
+    for (old, new) in &rename.moves {
+        transaction
+            .rename(Path::new(old), Path::new(new))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", old, e)))?;
+    }
+
+    let mut entry = lib.entries()[rename.entry].clone();
+    for (old, new) in &rename.moves {
+        if let Some(j) = entry.file_paths().iter().position(|p| p == old) {
+            entry.set_file_path(j, new.clone());
+        }
+    }
+    let paths = entry.file_paths().to_vec();
+    lib.replace_entry_stored(rename.entry, entry)?;
+    transaction.commit();
+
+    storage::update_symlinks(&paths)
+}
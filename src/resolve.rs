@@ -0,0 +1,295 @@
+//! Provides resolvers which look up the bibliography of a document by its identifier.
+
+use configuration::Configuration;
+use model::Identifier;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ResolveError {
+        /// Returned when the request to the resolver failed
+        Http(descr: String) {
+            display(self_) -> ("Request failed: {}", descr)
+        }
+        /// Returned when the resolver does not know the identifier
+        NotFound(descr: String) {
+            display(self_) -> ("Identifier not found: {}", descr)
+        }
+        /// Returned when the resolver can not handle the kind of identifier
+        Unsupported(descr: String) {
+            display(self_) -> ("Identifier not supported: {}", descr)
+        }
+        /// Returned when an I/O error occured while accessing the cache
+        Io(err: io::Error) {
+            display(self_) -> ("I/O error: {}", err)
+            from()
+        }
+    }
+}
+
+/// The DOI prefix under which arXiv registers DOIs for all of its preprints.
+const ARXIV_DOI_PREFIX: &str = "10.48550/arXiv.";
+
+/// A service which looks up the BibTeX bibliography of a document.
+pub trait Resolver {
+    fn resolve(&self, id: &Identifier) -> Result<String, ResolveError>;
+
+    /// Called once the record returned for `id` was parsed successfully.
+    fn accept(&self, _id: &Identifier, _record: &str) -> Result<(), ResolveError> {
+        Ok(())
+    }
+}
+
+/// Resolves DOIs using content negotiation as supported by doi.org and Crossref. arXiv
+/// identifiers are resolved using the DOIs arXiv assigns to its preprints.
+pub struct HttpResolver {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+/// Stores the records fetched by another resolver in a directory so they are available
+/// offline and do not need to be fetched again.
+pub struct CachedResolver<R: Resolver> {
+    inner: Option<R>,
+    directory: PathBuf,
+}
+
+impl HttpResolver {
+    pub fn new(base_url: &str) -> HttpResolver {
+        HttpResolver {
+            base_url: String::from(base_url.trim_end_matches('/')),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(15))
+                .build(),
+        }
+    }
+}
+
+impl Resolver for HttpResolver {
+    fn resolve(&self, id: &Identifier) -> Result<String, ResolveError> {
+        let doi = match id {
+            Identifier::Doi(doi) => doi.clone(),
+            Identifier::Arxiv(arxiv) => format!("{}{}", ARXIV_DOI_PREFIX, arxiv),
+            Identifier::Isbn(_) => return Err(ResolveError::Unsupported(id.to_string())),
+        };
+
+        let url = format!("{}/{}", self.base_url, doi);
+        match self
+            .agent
+            .get(&url)
+            .set("Accept", "application/x-bibtex; charset=utf-8")
+            .call()
+        {
+            Ok(response) => response
+                .into_string()
+                .map_err(|e| ResolveError::Http(e.to_string())),
+            Err(ureq::Error::Status(404, _)) => Err(ResolveError::NotFound(id.to_string())),
+            Err(e) => Err(ResolveError::Http(e.to_string())),
+        }
+    }
+}
+
+impl<R: Resolver> CachedResolver<R> {
+    /// Creates a resolver only looking up identifiers in the cache if `inner` is `None`.
+    pub fn new(inner: Option<R>, directory: PathBuf) -> CachedResolver<R> {
+        CachedResolver { inner, directory }
+    }
+
+    fn cache_path(&self, id: &Identifier) -> PathBuf {
+        let name: String = id
+            .to_string()
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.directory.join(format!("{}.bib", name))
+    }
+}
+
+impl<R: Resolver> Resolver for CachedResolver<R> {
+    fn resolve(&self, id: &Identifier) -> Result<String, ResolveError> {
+        let path = self.cache_path(id);
+        if let Ok(record) = fs::read_to_string(&path) {
+            return Ok(record);
+        }
+
+        let record = match self.inner {
+            Some(ref inner) => inner.resolve(id)?,
+            None => return Err(ResolveError::NotFound(format!("{} (offline)", id))),
+        };
+
+        Ok(record)
+    }
+
+    /// Only records which could be parsed are cached, so a malformed response is fetched
+    /// again the next time instead of being returned from the cache.
+    fn accept(&self, id: &Identifier, record: &str) -> Result<(), ResolveError> {
+        let path = self.cache_path(id);
+        if !path.exists() {
+            fs::create_dir_all(&self.directory)?;
+            fs::write(&path, record)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the resolver described by the configuration, which caches all fetched records next
+/// to the library file.
+pub fn from_cfg(conf: &Configuration) -> CachedResolver<HttpResolver> {
+    let vars = conf.variables();
+    let inner = if vars.lookup_online() {
+        Some(HttpResolver::new(vars.resolver_url()))
+    } else {
+        None
+    };
+
+    CachedResolver::new(inner, cache_directory(conf))
+}
+
+/// Returns the directory the fetched records are cached in.
+pub fn cache_directory(conf: &Configuration) -> PathBuf {
+    conf.variables()
+        .library_location()
+        .parent()
+        .map_or_else(|| PathBuf::from("cache"), |p| p.join("cache"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const RECORD: &str = "@article{doe2020, author={Doe, John}, title={T}, year={2020}}";
+
+    /// Serves a single request, answering `/10.1000/known` with a record and everything else
+    /// with 404, and returns the address along with a handle yielding the request head.
+    fn serve_once() -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let response = if head.starts_with("GET /10.1000/known ") {
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    RECORD.len(),
+                    RECORD
+                )
+            } else {
+                String::from(
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+            };
+            (&stream).write_all(response.as_bytes()).unwrap();
+            head
+        });
+
+        (address, handle)
+    }
+
+    /// Returns a fixed record and counts how often it was asked.
+    struct Counting {
+        calls: Cell<usize>,
+    }
+
+    impl Resolver for Counting {
+        fn resolve(&self, _id: &Identifier) -> Result<String, ResolveError> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(String::from(RECORD))
+        }
+    }
+
+    fn doi(doi: &str) -> Identifier {
+        Identifier::Doi(String::from(doi))
+    }
+
+    #[test]
+    fn http_resolver_requests_bibtex() {
+        let (address, server) = serve_once();
+        let record = HttpResolver::new(&format!("{}/", address))
+            .resolve(&doi("10.1000/known"))
+            .unwrap();
+        assert_eq!(record, RECORD);
+        let head = server.join().unwrap().to_lowercase();
+        assert!(head.contains("\r\naccept: application/x-bibtex; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn http_resolver_reports_unknown_identifiers() {
+        let (address, server) = serve_once();
+        let result = HttpResolver::new(&address).resolve(&doi("10.1000/unknown"));
+        assert!(matches!(result, Err(ResolveError::NotFound(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn http_resolver_does_not_support_isbns() {
+        let result = HttpResolver::new("http://127.0.0.1:9")
+            .resolve(&Identifier::Isbn(String::from("0201038013")));
+        assert!(matches!(result, Err(ResolveError::Unsupported(_))));
+    }
+
+    #[test]
+    fn cached_records_are_returned_without_asking() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = Counting {
+            calls: Cell::new(0),
+        };
+        let resolver = CachedResolver::new(Some(inner), dir.path().to_path_buf());
+        let id = doi("10.1000/cached");
+        fs::write(resolver.cache_path(&id), "cached").unwrap();
+
+        assert_eq!(resolver.resolve(&id).unwrap(), "cached");
+        assert_eq!(resolver.inner.as_ref().unwrap().calls.get(), 0);
+        assert_eq!(resolver.resolve(&doi("10.1000/new")).unwrap(), RECORD);
+        assert_eq!(resolver.inner.as_ref().unwrap().calls.get(), 1);
+    }
+
+    #[test]
+    fn offline_resolver_only_uses_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let resolver: CachedResolver<Counting> =
+            CachedResolver::new(None, dir.path().to_path_buf());
+        let result = resolver.resolve(&doi("10.1000/xyz"));
+        assert!(matches!(result, Err(ResolveError::NotFound(_))));
+    }
+
+    #[test]
+    fn records_are_only_cached_once_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = Counting {
+            calls: Cell::new(0),
+        };
+        let resolver = CachedResolver::new(Some(inner), dir.path().join("cache"));
+        let id = doi("10.1000/xyz");
+
+        resolver.resolve(&id).unwrap();
+        assert!(!resolver.cache_path(&id).exists());
+        resolver.accept(&id, "first").unwrap();
+        resolver.accept(&id, "second").unwrap();
+        assert_eq!(
+            fs::read_to_string(resolver.cache_path(&id)).unwrap(),
+            "first"
+        );
+    }
+}
@@ -0,0 +1,518 @@
+//! Provides the file operations used to place documents in the document directory.
+
+use configuration::LinkMode;
+use digest;
+use model::FileDigest;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Moves a file, falling back to copying and deleting it if the destination is on another
+/// filesystem. The copy is synced to disk and compared to `digest` before the source is
+/// deleted, so the document is never lost. An existing file at `to` is never replaced.
+pub fn move_file(from: &Path, to: &Path, digest: &FileDigest) -> io::Result<()> {
+    match rename_new(from, to) {
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => (),
+        result => return result,
+    }
+
+    copy_file(from, to, digest)?;
+    fs::remove_file(from)
+}
+
+/// Copies a file, making sure the copy is synced to disk and has the given digest. The data
+/// is written to a new temporary file next to `to` which is only renamed to `to` once it
+/// matches, so an existing file at `to` is never replaced.
+pub fn copy_file(from: &Path, to: &Path, digest: &FileDigest) -> io::Result<()> {
+    let mut temp = to.to_path_buf().into_os_string();
+    temp.push(".reed-copy");
+    let temp = PathBuf::from(temp);
+    let mut target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)?;
+
+    let result = File::open(from)
+        .and_then(|mut source| io::copy(&mut source, &mut target))
+        .and_then(|_| target.sync_all())
+        .and_then(|_| digest::calculate(&temp))
+        .and_then(|d| {
+            if d == *digest {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Copy of {} does not match the original", from.display()),
+                ))
+            }
+        })
+        .and_then(|_| rename_new(&temp, to));
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+
+    result
+}
+
+/// Renames a file, failing if `to` already exists. The file is hard linked to `to` first,
+/// which fails atomically for existing files, and falls back to a checked rename on
+/// filesystems without hard links.
+fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => {
+            if let Err(e) = fs::remove_file(from) {
+                fs::remove_file(to).ok();
+                return Err(e);
+            }
+            Ok(())
+        }
+        Err(ref e)
+            if e.kind() != io::ErrorKind::AlreadyExists && fs::symlink_metadata(to).is_err() =>
+        {
+            fs::rename(from, to)
+        }
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        )),
+    }
+}
+
+/// Returns the first of the names `name.ext`, `name-2.ext`, `name-3.ext` and so on which is
+/// not taken in any of the directories, so no document already in the library is overwritten.
+pub fn free_name<P: AsRef<Path>>(dirs: &[P], name: &str) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    let ext = path.extension().and_then(|e| e.to_str());
+    (1..)
+        .map(|n| match (n, ext) {
+            (1, _) => String::from(name),
+            (_, Some(ext)) => format!("{}-{}.{}", stem, n, ext),
+            (_, None) => format!("{}-{}", stem, n),
+        })
+        .find(|name| {
+            dirs.iter()
+                .all(|d| fs::symlink_metadata(d.as_ref().join(name)).is_err())
+        })
+        .unwrap_or_default()
+}
+
+/// Creates a copy of a document at `link` using the given mode. Symbolic links point to the
+/// canonical path of `original`.
+pub fn link(original: &Path, link: &Path, mode: LinkMode, digest: &FileDigest) -> io::Result<()> {
+    match mode {
+        LinkMode::Hardlink => fs::hard_link(original, link),
+        LinkMode::Symlink => symlink(&original.canonicalize()?, link),
+        LinkMode::Reflink => reflink(original, link).or_else(|_| copy_file(original, link, digest)),
+        LinkMode::Copy => copy_file(original, link, digest),
+    }
+}
+
+/// Creates a symbolic link to a file.
+#[cfg(unix)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Creates a copy sharing the data of the original using the `FICLONE` ioctl.
+#[cfg(target_os = "linux")]
+fn reflink(original: &Path, link: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = File::open(original)?;
+    let target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(link)?;
+    // The file descriptors are valid as long as both files are open
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if result == -1 {
+        let err = io::Error::last_os_error();
+        drop(target);
+        fs::remove_file(link).ok();
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_original: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Reflinks are not supported on this platform",
+    ))
+}
+
+/// Points the symbolic links among the copies of a document to the first copy again, e.g.
+/// after the first copy was moved.
+pub fn update_symlinks(paths: &[String]) -> io::Result<()> {
+    let original = match paths.first() {
+        Some(p) => Path::new(p).canonicalize()?,
+        None => return Ok(()),
+    };
+    for p in paths.iter().skip(1).map(Path::new) {
+        let is_symlink = fs::symlink_metadata(p).is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink && p.canonicalize().ok().as_ref() != Some(&original) {
+            let mut temp = p.to_path_buf().into_os_string();
+            temp.push(".reed-link");
+            let temp = PathBuf::from(temp);
+            symlink(&original, &temp)?;
+            fs::rename(&temp, p)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A file operation which was applied as part of a transaction.
+#[derive(Debug)]
+enum Operation {
+    CreatedDir(PathBuf),
+    Created(PathBuf),
+    Renamed(PathBuf, PathBuf),
+    Moved(PathBuf, PathBuf, FileDigest),
+    Removed(PathBuf, PathBuf),
+}
+
+/// Records file operations so they can be undone if a later step fails. All operations are
+/// undone in reverse order when the transaction is dropped without being committed.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    done: Vec<Operation>,
+    committed: bool,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    /// Creates a directory and all of its missing parents.
+    pub fn create_dir_all(&mut self, dir: &Path) -> io::Result<()> {
+        let missing: Vec<PathBuf> = dir
+            .ancestors()
+            .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(dir)?;
+        // Parents first, so they are removed last
+        for d in missing.into_iter().rev() {
+            self.done.push(Operation::CreatedDir(d));
+        }
+
+        Ok(())
+    }
+
+    pub fn move_file(&mut self, from: &Path, to: &Path, digest: &FileDigest) -> io::Result<()> {
+        move_file(from, to, digest)?;
+        self.done.push(Operation::Moved(
+            from.to_path_buf(),
+            to.to_path_buf(),
+            *digest,
+        ));
+
+        Ok(())
+    }
+
+    pub fn copy_file(&mut self, from: &Path, to: &Path, digest: &FileDigest) -> io::Result<()> {
+        copy_file(from, to, digest)?;
+        self.done.push(Operation::Created(to.to_path_buf()));
+
+        Ok(())
+    }
+
+    pub fn link(
+        &mut self,
+        original: &Path,
+        link_path: &Path,
+        mode: LinkMode,
+        digest: &FileDigest,
+    ) -> io::Result<()> {
+        link(original, link_path, mode, digest)?;
+        self.done.push(Operation::Created(link_path.to_path_buf()));
+
+        Ok(())
+    }
+
+    /// Renames a file within the same filesystem, refusing to overwrite existing files.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        if to.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", to.display()),
+            ));
+        }
+        fs::rename(from, to)?;
+        self.done
+            .push(Operation::Renamed(from.to_path_buf(), to.to_path_buf()));
+
+        Ok(())
+    }
+
+    /// Removes a file. The file is only set aside until the transaction is committed, so it can
+    /// be restored.
+    pub fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        let mut backup = path.to_path_buf().into_os_string();
+        backup.push(".reed-removed");
+        let backup = PathBuf::from(backup);
+        rename_new(path, &backup)?;
+        self.done
+            .push(Operation::Removed(path.to_path_buf(), backup));
+
+        Ok(())
+    }
+
+    /// Keeps all applied operations and deletes the removed files.
+    pub fn commit(mut self) {
+        self.committed = true;
+        for op in &self.done {
+            if let Operation::Removed(_, ref backup) = *op {
+                fs::remove_file(backup).ok();
+            }
+        }
+    }
+
+    /// Undoes all applied operations in reverse order. Undoing continues after a failed step
+    /// and the first error is returned.
+    pub fn rollback(&mut self) -> io::Result<()> {
+        let mut first_error = None;
+        while let Some(op) = self.done.pop() {
+            let result = match op {
+                Operation::CreatedDir(ref d) => fs::remove_dir(d),
+                Operation::Created(ref p) => fs::remove_file(p),
+                Operation::Renamed(ref from, ref to) => fs::rename(to, from),
+                Operation::Moved(ref from, ref to, ref digest) => move_file(to, from, digest),
+                Operation::Removed(ref path, ref backup) => fs::rename(backup, path),
+            };
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = self.rollback() {
+                eprintln!("Warning: Failed to undo file operations: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a file with the given content and returns its digest.
+    fn create(path: &Path, content: &str) -> FileDigest {
+        fs::write(path, content).unwrap();
+        digest::calculate(path).unwrap()
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn copy_file_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        let digest = create(&from, "document");
+
+        copy_file(&from, &to, &digest).unwrap();
+        assert_eq!(fs::read_to_string(&to).unwrap(), "document");
+        assert_eq!(names(dir.path()), ["a.pdf", "b.pdf"]);
+    }
+
+    #[test]
+    fn copy_file_never_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        let digest = create(&from, "document");
+        fs::write(&to, "other").unwrap();
+
+        let err = copy_file(&from, &to, &digest).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&to).unwrap(), "other");
+        assert_eq!(names(dir.path()), ["a.pdf", "b.pdf"]);
+    }
+
+    #[test]
+    fn copy_file_removes_mismatching_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        create(&from, "document");
+        let digest = create(&dir.path().join("c.pdf"), "other");
+
+        let err = copy_file(&from, &to, &digest).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(names(dir.path()), ["a.pdf", "c.pdf"]);
+    }
+
+    #[test]
+    fn copy_file_keeps_foreign_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        let digest = create(&from, "document");
+        fs::write(dir.path().join("b.pdf.reed-copy"), "someone else's").unwrap();
+
+        assert!(copy_file(&from, &to, &digest).is_err());
+        assert_eq!(names(dir.path()), ["a.pdf", "b.pdf.reed-copy"]);
+    }
+
+    #[test]
+    fn move_file_never_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        let digest = create(&from, "document");
+        fs::write(&to, "other").unwrap();
+
+        assert!(move_file(&from, &to, &digest).is_err());
+        assert_eq!(fs::read_to_string(&from).unwrap(), "document");
+        assert_eq!(fs::read_to_string(&to).unwrap(), "other");
+
+        fs::remove_file(&to).unwrap();
+        move_file(&from, &to, &digest).unwrap();
+        assert_eq!(names(dir.path()), ["b.pdf"]);
+    }
+
+    #[test]
+    fn move_file_copies_across_filesystems() {
+        // Requires a temporary file system separate from the one of the temporary directory
+        let shm = Path::new("/dev/shm");
+        let dir = tempfile::tempdir().unwrap();
+        let other = match tempfile::tempdir_in(shm) {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+        if file_device(dir.path()) == file_device(other.path()) {
+            return;
+        }
+        let (from, to) = (dir.path().join("a.pdf"), other.path().join("b.pdf"));
+        let digest = create(&from, "document");
+        assert_eq!(
+            fs::rename(&from, &to).unwrap_err().kind(),
+            io::ErrorKind::CrossesDevices
+        );
+
+        move_file(&from, &to, &digest).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "document");
+        assert_eq!(names(other.path()), ["b.pdf"]);
+    }
+
+    #[cfg(unix)]
+    fn file_device(path: &Path) -> Option<u64> {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).ok().map(|m| m.dev())
+    }
+
+    #[cfg(not(unix))]
+    fn file_device(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    #[test]
+    fn links_never_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        let digest = create(&from, "document");
+        fs::write(&to, "other").unwrap();
+
+        for &mode in &[
+            LinkMode::Hardlink,
+            LinkMode::Symlink,
+            LinkMode::Reflink,
+            LinkMode::Copy,
+        ] {
+            assert!(link(&from, &to, mode, &digest).is_err());
+            assert_eq!(fs::read_to_string(&to).unwrap(), "other");
+            assert_eq!(names(dir.path()), ["a.pdf", "b.pdf"]);
+        }
+    }
+
+    #[test]
+    fn free_name_appends_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let dirs = [dir.path(), other.path()];
+        assert_eq!(free_name(&dirs, "paper.pdf"), "paper.pdf");
+
+        fs::write(dir.path().join("paper.pdf"), "").unwrap();
+        assert_eq!(free_name(&dirs, "paper.pdf"), "paper-2.pdf");
+        fs::write(other.path().join("paper-2.pdf"), "").unwrap();
+        assert_eq!(free_name(&dirs, "paper.pdf"), "paper-3.pdf");
+
+        // Dangling symbolic links take up their name as well
+        symlink(&dir.path().join("missing"), &dir.path().join("notes")).unwrap();
+        assert_eq!(free_name(&dirs, "notes"), "notes-2");
+        assert_eq!(free_name(&dirs, "a.b.pdf"), "a.b.pdf");
+    }
+
+    #[test]
+    fn dropped_transactions_restore_moved_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("a.pdf"), dir.path().join("b.pdf"));
+        let digest = create(&from, "document");
+
+        {
+            let mut transaction = Transaction::new();
+            transaction.move_file(&from, &to, &digest).unwrap();
+            assert_eq!(names(dir.path()), ["b.pdf"]);
+        }
+        assert_eq!(fs::read_to_string(&from).unwrap(), "document");
+        assert_eq!(names(dir.path()), ["a.pdf"]);
+    }
+
+    #[test]
+    fn removed_files_are_kept_until_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.pdf");
+        create(&path, "document");
+
+        let mut transaction = Transaction::new();
+        transaction.remove_file(&path).unwrap();
+        assert_eq!(names(dir.path()), ["a.pdf.reed-removed"]);
+        transaction.rollback().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "document");
+        assert_eq!(names(dir.path()), ["a.pdf"]);
+
+        let mut transaction = Transaction::new();
+        transaction.remove_file(&path).unwrap();
+        transaction.commit();
+        assert!(names(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn rollback_only_removes_created_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("a");
+        fs::create_dir(&existing).unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction.create_dir_all(&existing.join("b/c")).unwrap();
+        assert!(existing.join("b/c").is_dir());
+        transaction.rollback().unwrap();
+        assert!(existing.is_dir());
+        assert!(names(&existing).is_empty());
+    }
+}
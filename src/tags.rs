@@ -0,0 +1,473 @@
+//! Manages the tags of library entries and the directories their documents are linked into.
+
+use configuration::Configuration;
+use library::Library;
+use resolve;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use storage;
+use storage::Transaction;
+
+/// The separator between the levels of hierarchical tags like `thesis/related-work/gnn`.
+pub const SEPARATOR: char = '/';
+
+/// Normalizes a hierarchical tag by trimming its levels and removing empty levels, making sure
+/// it can be used as a directory inside the document directory.
+pub fn normalize(tag: &str) -> io::Result<String> {
+    let levels: Vec<&str> = tag
+        .split(SEPARATOR)
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    let valid = !levels.is_empty()
+        && levels.iter().all(|l| {
+            let mut components = Path::new(l).components();
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+        });
+    if valid {
+        Ok(levels.join(&SEPARATOR.to_string()))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid tag {}", tag),
+        ))
+    }
+}
+
+/// Normalizes a tag which is about to be assigned to entries. Tags whose directory would
+/// collide with the directory of the views or the cache of the resolver are rejected.
+pub fn normalize_new(tag: &str, conf: &Configuration) -> io::Result<String> {
+    let tag = normalize(tag)?;
+    let dir = directory(&tag, conf);
+    let reserved = [
+        conf.variables().views_location(),
+        resolve::cache_directory(conf),
+    ];
+    match reserved
+        .iter()
+        .find(|r| dir.starts_with(r) || r.starts_with(&dir))
+    {
+        Some(r) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Tag {} collides with {}", tag, r.display()),
+        )),
+        None => Ok(tag),
+    }
+}
+
+/// Returns the tag and all of its parents, e.g. `a`, `a/b` and `a/b/c` for `a/b/c`.
+pub fn ancestors(tag: &str) -> Vec<&str> {
+    tag.match_indices(SEPARATOR)
+        .map(|(i, _)| &tag[..i])
+        .chain(Some(tag))
+        .collect()
+}
+
+/// Whether a tag equals `parent` or is one of its children.
+pub fn is_within(tag: &str, parent: &str) -> bool {
+    tag == parent || (tag.starts_with(parent) && tag[parent.len()..].starts_with(SEPARATOR))
+}
+
+/// Returns the directory the documents with the given tag are linked into. The directories of
+/// hierarchical tags are nested.
+pub fn directory(tag: &str, conf: &Configuration) -> PathBuf {
+    tag.split(SEPARATOR).fold(
+        conf.variables().document_location().to_path_buf(),
+        |d, l| d.join(l),
+    )
+}
+
+/// Removes the directory of a tag and its parents as long as they are empty.
+fn remove_empty_dirs(dir: &Path, conf: &Configuration) {
+    let root = conf.variables().document_location();
+    for d in dir.ancestors() {
+        if d == root || !d.starts_with(root) || fs::remove_dir(d).is_err() {
+            break;
+        }
+    }
+}
+
+/// Returns the index of the file path of an entry located in the given directory.
+fn position_in(paths: &[String], dir: &Path) -> Option<usize> {
+    paths
+        .iter()
+        .position(|p| Path::new(p).parent() == Some(dir))
+}
+
+fn file_name(path: &str) -> io::Result<&std::ffi::OsStr> {
+    Path::new(path).file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} has no file name", path),
+        )
+    })
+}
+
+fn path_string(path: &Path) -> io::Result<String> {
+    path.to_str()
+        .map(String::from)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Path is not valid UTF-8"))
+}
+
+/// Lists all tags in use and their parents ordered as a tree, together with the number of
+/// entries tagged with them or one of their children.
+pub fn list(lib: &Library) -> Vec<(String, usize)> {
+    let mut tags: BTreeMap<Vec<&str>, usize> = BTreeMap::new();
+    for entry in lib.entries() {
+        let mut within: Vec<&str> = entry.tags().iter().flat_map(|t| ancestors(t)).collect();
+        within.sort_unstable();
+        within.dedup();
+        for tag in within {
+            *tags.entry(tag.split(SEPARATOR).collect()).or_insert(0) += 1;
+        }
+    }
+
+    tags.into_iter()
+        .map(|(levels, count)| (levels.join(&SEPARATOR.to_string()), count))
+        .collect()
+}
+
+/// Tags an entry and links its document into the directory of the tag. Returns `false` if
+/// the entry already had the tag.
+pub fn add(lib: &mut Library, index: usize, tag: &str, conf: &Configuration) -> io::Result<bool> {
+    let tag = &normalize_new(tag, conf)?;
+    let entry = &lib.entries()[index];
+    if entry.tags().iter().any(|t| t == tag) {
+        return Ok(false);
+    }
+    let source = entry
+        .file_paths()
+        .iter()
+        .find(|p| Path::new(p).exists())
+        .cloned()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No file of {} exists", entry.meta().key()),
+            )
+        })?;
+    let dir = directory(tag, conf);
+    let name = storage::free_name(&[&dir], &file_name(&source)?.to_string_lossy());
+    let path = dir.join(name);
+    let path_str = path_string(&path)?;
+
+    let mut transaction = Transaction::new();
+    transaction.create_dir_all(&dir)?;
+    let mode = conf.variables().tag_link_mode();
+    transaction
+        .link(Path::new(&source), &path, mode, entry.digest())
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let mut entry = entry.clone();
+    entry.add_tag(tag.clone(), path_str);
+    lib.replace_entry_stored(index, entry)?;
+    transaction.commit();
+
+    Ok(true)
+}
+
+/// Removes a tag from an entry and deletes the document linked into the directory of the tag.
+/// If this is the only file of the entry, it is moved to the document directory instead.
+/// Returns `false` if the entry did not have the tag.
+pub fn remove(
+    lib: &mut Library,
+    index: usize,
+    tag: &str,
+    conf: &Configuration,
+) -> io::Result<bool> {
+    let tag = &normalize(tag)?;
+    let mut entry = lib.entries()[index].clone();
+    if !entry.tags().iter().any(|t| t == tag) {
+        return Ok(false);
+    }
+    let dir = directory(tag, conf);
+    let tags: Vec<String> = entry.tags().iter().filter(|t| *t != tag).cloned().collect();
+    let mut paths = entry.file_paths().to_vec();
+
+    let mut transaction = Transaction::new();
+    let position = position_in(&paths, &dir);
+    if let Some(j) = position {
+        if paths.len() > 1 && j == 0 {
+            // The first copy is the one the others are linked to, so it replaces the next one
+            transaction.remove_file(Path::new(&paths[1]))?;
+            transaction.rename(Path::new(&paths[0]), Path::new(&paths[1]))?;
+            paths.remove(0);
+        } else if paths.len() > 1 {
+            transaction.remove_file(Path::new(&paths[j]))?;
+            paths.remove(j);
+        } else {
+            let root = conf.variables().document_location();
+            let name = storage::free_name(&[root], &file_name(&paths[j])?.to_string_lossy());
+            let target = root.join(name);
+            transaction.rename(Path::new(&paths[j]), &target)?;
+            paths[j] = path_string(&target)?;
+        }
+    }
+
+    entry.set_tags(tags);
+    entry.set_file_paths(paths);
+    lib.replace_entry_stored(index, entry)?;
+    transaction.commit();
+
+    if position.is_some() {
+        remove_empty_dirs(&dir, conf);
+    }
+    storage::update_symlinks(lib.entries()[index].file_paths())?;
+
+    Ok(true)
+}
+
+/// Renames a tag along with all of its children for all entries and moves the directories of
+/// the tags. Returns the number of renamed entries.
+pub fn rename(lib: &mut Library, old: &str, new: &str, conf: &Configuration) -> io::Result<usize> {
+    let old = &normalize(old)?;
+    let new = &normalize_new(new, conf)?;
+    if old == new {
+        return Ok(0);
+    }
+    if is_within(new, old) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can not move tag {} into itself", old),
+        ));
+    }
+    let indices: Vec<usize> = lib
+        .entries()
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.tags().iter().any(|t| is_within(t, old)))
+        .map(|(i, _)| i)
+        .collect();
+    if indices.is_empty() {
+        return Ok(0);
+    }
+    let old_dir = directory(old, conf);
+    let new_dir = directory(new, conf);
+
+    // Move the whole directory tree if possible, else every document on its own
+    let mut transaction = Transaction::new();
+    let move_dir = old_dir.is_dir() && !new_dir.exists();
+    if move_dir {
+        if let Some(parent) = new_dir.parent() {
+            transaction.create_dir_all(parent)?;
+        }
+        transaction.rename(&old_dir, &new_dir)?;
+    }
+
+    let mut updates = Vec::new();
+    let mut emptied: Vec<PathBuf> = old_dir
+        .parent()
+        .map(Path::to_path_buf)
+        .into_iter()
+        .collect();
+    for &i in &indices {
+        let entry = &lib.entries()[i];
+        let mut tags: Vec<String> = entry.tags().to_vec();
+        let mut paths = entry.file_paths().to_vec();
+        for tag in entry.tags().iter().filter(|t| is_within(t, old)) {
+            let renamed = format!("{}{}", new, &tag[old.len()..]);
+            let from_dir = directory(tag, conf);
+            let to_dir = directory(&renamed, conf);
+            let j = position_in(&paths, &from_dir);
+            if !move_dir {
+                emptied.push(from_dir);
+            }
+
+            // The entry already has the renamed tag, so the document is already linked there
+            if tags.contains(&renamed) {
+                tags.retain(|t| t != tag);
+                match (j, position_in(&paths, &to_dir)) {
+                    (Some(0), Some(k)) => {
+                        // The first copy is the one the others are linked to, so it replaces
+                        // the copy of the renamed tag
+                        if !move_dir {
+                            transaction.remove_file(Path::new(&paths[k]))?;
+                            transaction.rename(Path::new(&paths[0]), Path::new(&paths[k]))?;
+                        }
+                        paths.swap(0, k);
+                        paths.remove(k);
+                    }
+                    (Some(j), _) => {
+                        if !move_dir {
+                            transaction.remove_file(Path::new(&paths[j]))?;
+                        }
+                        paths.remove(j);
+                    }
+                    (None, _) => (),
+                }
+                continue;
+            }
+
+            if let Some(t) = tags.iter_mut().find(|t| *t == tag) {
+                *t = renamed;
+            }
+            if let Some(j) = j {
+                let target = to_dir.join(file_name(&paths[j])?);
+                if !move_dir {
+                    transaction.create_dir_all(&to_dir)?;
+                    transaction.rename(Path::new(&paths[j]), &target)?;
+                }
+                paths[j] = path_string(&target)?;
+            }
+        }
+        let mut entry = entry.clone();
+        entry.set_tags(tags);
+        entry.set_file_paths(paths);
+        updates.push((i, entry));
+    }
+    lib.replace_entries_stored(updates)?;
+    transaction.commit();
+
+    let mut first_error = None;
+    for &i in &indices {
+        if let Err(e) = storage::update_symlinks(lib.entries()[i].file_paths()) {
+            first_error.get_or_insert(e);
+        }
+    }
+    // Children are removed before their parents
+    emptied.sort_unstable_by(|a, b| b.cmp(a));
+    for d in emptied {
+        remove_empty_dirs(&d, conf);
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(indices.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import::import_for_tests as import;
+
+    /// Describes the files of an entry relative to the document directory, like `b/x.pdf` for
+    /// a regular file and `c/x.pdf -> b/x.pdf` for a symbolic link.
+    fn files(lib: &Library, index: usize, conf: &Configuration) -> Vec<String> {
+        let root = conf.variables().document_location();
+        let relative = |p: &Path| p.strip_prefix(root).unwrap().display().to_string();
+        lib.entries()[index]
+            .file_paths()
+            .iter()
+            .map(|p| {
+                let p = Path::new(p);
+                assert!(p.exists(), "{} is missing", p.display());
+                match fs::read_link(p) {
+                    Ok(target) => format!("{} -> {}", relative(p), relative(&target)),
+                    Err(_) => relative(p),
+                }
+            })
+            .collect()
+    }
+
+    /// Lists the tag directories in the document directory.
+    fn dirs(conf: &Configuration) -> Vec<String> {
+        let mut dirs: Vec<String> = fs::read_dir(conf.variables().document_location())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.is_dir())
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        dirs.sort();
+        dirs
+    }
+
+    #[test]
+    fn removing_the_tag_of_the_first_copy_keeps_the_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "tag_link_mode: symlink");
+        let mut lib = Library::new(conf.variables().library_location());
+        let i = import(&mut lib, "X", &["a", "b", "c"], &conf);
+        assert_eq!(
+            files(&lib, i, &conf),
+            [
+                "a/DoeJohn-20-X.pdf",
+                "b/DoeJohn-20-X.pdf -> a/DoeJohn-20-X.pdf",
+                "c/DoeJohn-20-X.pdf -> a/DoeJohn-20-X.pdf",
+            ]
+        );
+
+        assert!(remove(&mut lib, i, "a", &conf).unwrap());
+        assert_eq!(lib.entries()[i].tags(), ["b", "c"]);
+        assert_eq!(
+            files(&lib, i, &conf),
+            [
+                "b/DoeJohn-20-X.pdf",
+                "c/DoeJohn-20-X.pdf -> b/DoeJohn-20-X.pdf",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&lib.entries()[i].file_paths()[0]).unwrap(),
+            "X"
+        );
+        assert_eq!(dirs(&conf), ["b", "c"]);
+        assert!(!remove(&mut lib, i, "a", &conf).unwrap());
+    }
+
+    #[test]
+    fn renaming_moves_the_tag_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "tag_link_mode: symlink");
+        let mut lib = Library::new(conf.variables().library_location());
+        let i = import(&mut lib, "X", &["a/x", "b"], &conf);
+        let j = import(&mut lib, "Y", &["a"], &conf);
+
+        assert_eq!(rename(&mut lib, "a", "c/d", &conf).unwrap(), 2);
+        assert_eq!(lib.entries()[i].tags(), ["c/d/x", "b"]);
+        assert_eq!(
+            files(&lib, i, &conf),
+            [
+                "c/d/x/DoeJohn-20-X.pdf",
+                "b/DoeJohn-20-X.pdf -> c/d/x/DoeJohn-20-X.pdf",
+            ]
+        );
+        assert_eq!(lib.entries()[j].tags(), ["c/d"]);
+        assert_eq!(files(&lib, j, &conf), ["c/d/DoeJohn-20-Y.pdf"]);
+        assert_eq!(dirs(&conf), ["b", "c"]);
+    }
+
+    #[test]
+    fn renaming_into_an_existing_directory_moves_every_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "tag_link_mode: symlink");
+        let mut lib = Library::new(conf.variables().library_location());
+        let i = import(&mut lib, "X", &["a", "c"], &conf);
+        let j = import(&mut lib, "Y", &["b"], &conf);
+
+        assert_eq!(rename(&mut lib, "a", "b", &conf).unwrap(), 1);
+        assert_eq!(lib.entries()[i].tags(), ["b", "c"]);
+        assert_eq!(
+            files(&lib, i, &conf),
+            [
+                "b/DoeJohn-20-X.pdf",
+                "c/DoeJohn-20-X.pdf -> b/DoeJohn-20-X.pdf",
+            ]
+        );
+        assert_eq!(files(&lib, j, &conf), ["b/DoeJohn-20-Y.pdf"]);
+        assert_eq!(dirs(&conf), ["b", "c"]);
+    }
+
+    #[test]
+    fn renaming_onto_a_tag_of_the_entry_merges_the_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configuration::for_tests(dir.path(), "tag_link_mode: symlink");
+        let mut lib = Library::new(conf.variables().library_location());
+        let i = import(&mut lib, "X", &["a", "b", "c"], &conf);
+
+        assert_eq!(rename(&mut lib, "a", "b", &conf).unwrap(), 1);
+        assert_eq!(lib.entries()[i].tags(), ["b", "c"]);
+        assert_eq!(
+            files(&lib, i, &conf),
+            [
+                "b/DoeJohn-20-X.pdf",
+                "c/DoeJohn-20-X.pdf -> b/DoeJohn-20-X.pdf",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&lib.entries()[i].file_paths()[0]).unwrap(),
+            "X"
+        );
+        assert_eq!(dirs(&conf), ["b", "c"]);
+    }
+}
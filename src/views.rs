@@ -0,0 +1,175 @@
+//! Builds directory trees of symbolic links to the documents of the library from the views
+//! defined in the configuration.
+
+use configuration::util::expand_pattern;
+use configuration::{Configuration, ViewDefinition};
+use library::{Library, QueryError};
+use model::LibraryEntry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use storage;
+
+/// The changes made to the views directory.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub created: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub unchanged: usize,
+}
+
+/// Expands the path of a view for an entry into the directories the entry is linked into,
+/// relative to the views directory. `%G` is replaced by every tag of the entry, so entries
+/// without tags are not part of views using it.
+fn directories(view: &ViewDefinition, entry: &LibraryEntry, conf: &Configuration) -> Vec<PathBuf> {
+    let original_name = entry
+        .file_paths()
+        .first()
+        .and_then(|p| Path::new(p).file_stem())
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let patterns: Vec<String> = if view.path().contains("%G") {
+        entry
+            .tags()
+            .iter()
+            .map(|t| view.path().replace("%G", t))
+            .collect()
+    } else {
+        vec![String::from(view.path())]
+    };
+
+    patterns
+        .iter()
+        .map(|p| expand_pattern(p, &original_name, entry.meta(), conf))
+        .map(|p| {
+            // Expandos may be empty and must not leave the views directory
+            Path::new(&p)
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(c) => Some(c),
+                    _ => None,
+                })
+                .collect::<PathBuf>()
+        })
+        .collect()
+}
+
+/// Determines the links of all views mapped to the documents they point to.
+pub fn plan(lib: &Library, conf: &Configuration) -> Result<BTreeMap<PathBuf, PathBuf>, QueryError> {
+    let root = conf.variables().views_location();
+    let mut links: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+
+    for view in conf.variables().views() {
+        let indices = match lib.query_indices(&view.query().params()) {
+            Ok(indices) => indices,
+            Err(QueryError::NoMatch) => continue,
+            Err(e) => return Err(e),
+        };
+        for i in indices {
+            let entry = &lib.entries()[i];
+            let target = match entry
+                .file_paths()
+                .iter()
+                .find_map(|p| Path::new(p).canonicalize().ok())
+            {
+                Some(t) => t,
+                None => continue,
+            };
+            let stem = target
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let ext = target
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()))
+                .unwrap_or_default();
+
+            for dir in directories(view, entry, conf) {
+                let dir = root.join(dir);
+                // Documents with the same file name in one directory are numbered
+                let link = (1..)
+                    .map(|n| {
+                        if n == 1 {
+                            dir.join(format!("{}{}", stem, ext))
+                        } else {
+                            dir.join(format!("{}-{}{}", stem, n, ext))
+                        }
+                    })
+                    .find(|l| links.get(l).is_none_or(|t| *t == target))
+                    .unwrap_or_default();
+                links.insert(link, target.clone());
+            }
+        }
+    }
+
+    Ok(links)
+}
+
+/// Lists the symbolic links and directories below a directory without following links.
+fn walk(root: &Path) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut links = Vec::new();
+    let mut dirs = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let file_type = dir_entry.file_type()?;
+            if file_type.is_symlink() {
+                links.push(dir_entry.path());
+            } else if file_type.is_dir() {
+                dirs.push(dir_entry.path());
+                pending.push(dir_entry.path());
+            }
+        }
+    }
+
+    Ok((links, dirs))
+}
+
+/// Rebuilds the views directory: links missing from the views are created, links no longer
+/// part of any view or pointing to the wrong document are removed and empty directories are
+/// deleted. Files which are no symbolic links are never touched.
+pub fn sync(lib: &Library, conf: &Configuration, dry_run: bool) -> Result<SyncReport, QueryError> {
+    let mut links = plan(lib, conf)?;
+    let root = conf.variables().views_location();
+    let mut report = SyncReport::default();
+
+    let (existing, mut dirs) = if root.is_dir() {
+        walk(&root)?
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    for link in existing {
+        let current = fs::read_link(&link)?;
+        if links.get(&link) == Some(&current) {
+            links.remove(&link);
+            report.unchanged += 1;
+            continue;
+        }
+        if !dry_run {
+            fs::remove_file(&link)?;
+        }
+        report.removed.push(link);
+    }
+
+    for (link, target) in links {
+        if !dry_run {
+            if let Some(dir) = link.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            storage::symlink(&target, &link)?;
+        }
+        report.created.push(link);
+    }
+
+    if !dry_run {
+        // Children are removed before their parents, directories still in use are kept
+        dirs.sort_unstable_by(|a, b| b.cmp(a));
+        for d in dirs {
+            fs::remove_dir(&d).ok();
+        }
+    }
+
+    Ok(report)
+}